@@ -0,0 +1,411 @@
+//! Benchmarks of `GetKeys` and its hot paths, comparing them to how they were done before.
+//!
+//! Run with `cargo bench`. Reading the samples and `GetKeys` are measured while another thread
+//! processes new states as fast as it can, which is the worst case for contention. With a single
+//! core, the slowest calls are set by the scheduler rather than by waiting for the writer. The
+//! number of heap allocations made by `GetKeys` and the new paths is counted, and has to be zero.
+
+use mupen64plus_input_gca::{
+    adapter::{AdapterState, Channel, ControllerState},
+    config::{Chord, ChordMapping, Config, GcButton, Layer, N64Button, StickSampling},
+    init_stub,
+    mapping::MappingTable,
+    process_stub_state,
+    sampling::{SampleChannel, Samples},
+    GetKeys,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    hint::black_box,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Counts the allocations of each thread, so that the allocations of the writer threads are not
+/// counted for the benchmarked code.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: u32 = 200_000;
+
+/// Run `f` `ITERATIONS` times, and print the mean time it took, and how long the slowest calls
+/// took. Returns the number of heap allocations made by `f`.
+fn bench(name: &str, mut f: impl FnMut(u32)) -> usize {
+    let mut times = vec![Duration::ZERO; ITERATIONS as usize];
+    for i in 0..ITERATIONS / 10 {
+        f(i);
+    }
+
+    let allocations_before = allocations();
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        f(i);
+    }
+    let mean = start.elapsed() / ITERATIONS;
+
+    // Timed separately, since timing each call takes longer than some of the calls
+    for (i, time) in (0..ITERATIONS).zip(&mut times) {
+        let t = Instant::now();
+        f(i);
+        *time = t.elapsed();
+    }
+    let allocations = allocations() - allocations_before;
+
+    times.sort_unstable();
+    let p999 = times[times.len() * 999 / 1000];
+    println!(
+        "{name:<34} {:>6} ns/iter, p99.9 {:>6} ns, max {:>9} ns, {allocations} allocations",
+        mean.as_nanos(),
+        p999.as_nanos(),
+        times[times.len() - 1].as_nanos()
+    );
+    allocations
+}
+
+fn state(i: u32) -> ControllerState {
+    ControllerState {
+        status: 0x10,
+        a: i.is_multiple_of(3),
+        b: i.is_multiple_of(5),
+        x: i.is_multiple_of(7),
+        z: i.is_multiple_of(11),
+        l: i.is_multiple_of(13),
+        stick_x: (i % 256) as u8,
+        stick_y: 128,
+        substick_x: 128,
+        substick_y: 128,
+        trigger_left: (i % 256) as u8,
+        ..ControllerState::new()
+    }
+}
+
+fn adapter_state(i: u32) -> AdapterState {
+    let s = state(i);
+    AdapterState {
+        controller_0: s,
+        controller_1: s,
+        controller_2: s,
+        controller_3: s,
+    }
+}
+
+/// Run `write` on another thread until `read` has been benchmarked.
+fn with_writer(write: impl FnMut(u32) + Send + 'static, read: impl FnOnce()) {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    STOP.store(false, Ordering::Relaxed);
+
+    let writer = thread::spawn(move || {
+        let mut write = write;
+        let mut i = 0;
+        while !STOP.load(Ordering::Relaxed) {
+            write(i);
+            i = i.wrapping_add(1);
+        }
+    });
+    read();
+    STOP.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+}
+
+/// The samples as they were before they were published through a triple buffer: the states since
+/// the last call to `GetKeys` are combined as they are added, and taken by `GetKeys`.
+mod before {
+    use mupen64plus_input_gca::{
+        adapter::ControllerState,
+        config::{GcButton, StickSampling},
+    };
+    use std::time::Instant;
+
+    #[derive(Debug, Copy, Clone)]
+    struct StickSamples {
+        sum: (u32, u32),
+        extremum: (u8, u8),
+        extremum_distance: i32,
+    }
+
+    impl StickSamples {
+        const fn new() -> Self {
+            Self {
+                sum: (0, 0),
+                extremum: (0, 0),
+                extremum_distance: -1,
+            }
+        }
+
+        fn add(&mut self, x: u8, y: u8, (dx, dy): (i8, i8)) {
+            self.sum.0 += x as u32;
+            self.sum.1 += y as u32;
+
+            let distance = dx as i32 * dx as i32 + dy as i32 * dy as i32;
+            if distance > self.extremum_distance {
+                self.extremum = (x, y);
+                self.extremum_distance = distance;
+            }
+        }
+
+        fn sample(&self, sampling: StickSampling, count: u32, latest: (u8, u8)) -> (u8, u8) {
+            match sampling {
+                StickSampling::Latest => latest,
+                StickSampling::Average => (
+                    ((self.sum.0 + count / 2) / count) as u8,
+                    ((self.sum.1 + count / 2) / count) as u8,
+                ),
+                StickSampling::Extremum => self.extremum,
+            }
+        }
+    }
+
+    // The fields are only passed to `black_box`
+    #[allow(dead_code)]
+    #[derive(Debug, Copy, Clone)]
+    pub struct Sample {
+        pub state: ControllerState,
+        pub read_at: Option<Instant>,
+        pub pressed_at: Option<Instant>,
+    }
+
+    #[derive(Debug, Copy, Clone)]
+    pub struct Samples {
+        latest: ControllerState,
+        read_at: Option<Instant>,
+        pressed_at: Option<Instant>,
+        combined: ControllerState,
+        count: u32,
+        stick: StickSamples,
+        substick: StickSamples,
+    }
+
+    impl Samples {
+        pub const fn new() -> Self {
+            Self {
+                latest: ControllerState::new(),
+                read_at: None,
+                pressed_at: None,
+                combined: ControllerState::new(),
+                count: 0,
+                stick: StickSamples::new(),
+                substick: StickSamples::new(),
+            }
+        }
+
+        pub fn add(&mut self, s: &ControllerState, now: Instant) {
+            let mut c = *s;
+            if self.count > 0 {
+                let prev = &self.combined;
+                c.a |= prev.a;
+                c.b |= prev.b;
+                c.x |= prev.x;
+                c.y |= prev.y;
+                c.left |= prev.left;
+                c.right |= prev.right;
+                c.down |= prev.down;
+                c.up |= prev.up;
+                c.start |= prev.start;
+                c.z |= prev.z;
+                c.r |= prev.r;
+                c.l |= prev.l;
+                c.trigger_left = c.trigger_left.max(prev.trigger_left);
+                c.trigger_right = c.trigger_right.max(prev.trigger_right);
+                c.bound_buttons |= prev.bound_buttons;
+            }
+
+            let pressed = GcButton::ALL
+                .iter()
+                .any(|b| b.is_pressed(s) && !b.is_pressed(&self.latest));
+            if pressed && self.pressed_at.is_none() {
+                self.pressed_at = Some(now);
+            }
+
+            self.latest = *s;
+            self.read_at = Some(now);
+            self.combined = c;
+            self.count += 1;
+            self.stick.add(s.stick_x, s.stick_y, s.stick());
+            self.substick.add(s.substick_x, s.substick_y, s.substick());
+        }
+
+        pub fn take(&mut self, sampling: StickSampling) -> Sample {
+            if self.count == 0 {
+                return Sample {
+                    state: self.latest,
+                    read_at: self.read_at,
+                    pressed_at: None,
+                };
+            }
+
+            let mut s = self.combined;
+            let latest = (s.stick_x, s.stick_y);
+            (s.stick_x, s.stick_y) = self.stick.sample(sampling, self.count, latest);
+            let latest = (s.substick_x, s.substick_y);
+            (s.substick_x, s.substick_y) = self.substick.sample(sampling, self.count, latest);
+
+            self.count = 0;
+            self.stick = StickSamples::new();
+            self.substick = StickSamples::new();
+            Sample {
+                state: s,
+                read_at: self.read_at,
+                pressed_at: self.pressed_at.take(),
+            }
+        }
+    }
+}
+
+fn bench_samples() -> usize {
+    // Before: the adapter thread and `GetKeys` share the samples and the adapter state behind
+    // mutexes
+    static ADAPTER_STATE: Mutex<AdapterState> = Mutex::new(AdapterState::new());
+    static ADAPTER_SAMPLES: Mutex<[before::Samples; 4]> = Mutex::new([before::Samples::new(); 4]);
+    with_writer(
+        |i| {
+            let state = adapter_state(i);
+            let now = Instant::now();
+            let mut samples = ADAPTER_SAMPLES.lock().unwrap();
+            for (channel, samples) in samples.iter_mut().enumerate() {
+                samples.add(&state.controller_state(channel.try_into().unwrap()), now);
+            }
+            drop(samples);
+            *ADAPTER_STATE.lock().unwrap() = state;
+        },
+        || {
+            bench("samples: mutex (before)", |_| {
+                let sample = ADAPTER_SAMPLES.lock().unwrap()[0].take(StickSampling::Latest);
+                black_box(sample);
+            });
+        },
+    );
+
+    // After: the adapter thread publishes the samples through a triple buffer
+    static CHANNEL: SampleChannel = SampleChannel::new();
+    let mut allocations = 0;
+    let mut samples = [Samples::new(); 4];
+    with_writer(
+        move |i| {
+            let state = adapter_state(i);
+            let now = Instant::now();
+            for (channel, samples) in samples.iter_mut().enumerate() {
+                samples.add(&state.controller_state(channel.try_into().unwrap()), now);
+            }
+            // Safety: this is the only thread that publishes
+            unsafe { CHANNEL.publish(&mut samples, [None; 4], StickSampling::Latest) };
+        },
+        || {
+            allocations = bench("samples: triple buffer (after)", |_| {
+                // Safety: this is the only thread that reads
+                let frame = unsafe { CHANNEL.read() };
+                black_box(CHANNEL.take(Channel::One, &frame));
+            });
+        },
+    );
+    allocations
+}
+
+/// The default config, with chords and a layer to go through when mapping.
+fn config() -> Config {
+    let mut cfg = Config {
+        chords: vec![
+            ChordMapping {
+                buttons: Chord(vec![GcButton::X, GcButton::Y]),
+                press: N64Button::Start,
+            },
+            ChordMapping {
+                buttons: Chord(vec![GcButton::A, GcButton::L]),
+                press: N64Button::CUp,
+            },
+        ],
+        ..Config::default()
+    };
+    cfg.layers = vec![Layer {
+        modifier: GcButton::Z,
+        mapping: cfg.controller_mapping.clone(),
+    }];
+    cfg
+}
+
+fn bench_mapping() -> usize {
+    let cfg = config();
+    let states: Vec<_> = (0..1024).map(state).collect();
+
+    // Before: the chords, layers and mapping are evaluated from the config
+    bench("mapping: config (before)", |i| {
+        let s = &states[i as usize % states.len()];
+        let mut value = 0;
+        let mut buttons = *s;
+        for chord in &cfg.chords {
+            if chord.buttons.is_held(s) {
+                value |= chord.press.bit_pattern();
+                for b in &chord.buttons.0 {
+                    b.set_pressed(&mut buttons, false);
+                }
+            }
+        }
+        let (mapping, modifier) = cfg.mapping(s);
+        if let Some(modifier) = modifier {
+            modifier.set_pressed(&mut buttons, false);
+        }
+        value |= mapping.bit_pattern(&buttons);
+        black_box((value, buttons.l, buttons.trigger_left));
+    });
+
+    // After: the config is compiled to a table once
+    let table = MappingTable::new(&cfg);
+    bench("mapping: compiled table (after)", |i| {
+        let mapped = table.map(Channel::One, &states[i as usize % states.len()]);
+        black_box((mapped.bits, mapped.left));
+    })
+}
+
+/// Benchmark the whole of `GetKeys`, with the states processed by the adapter thread.
+fn bench_get_keys() -> usize {
+    init_stub(config());
+
+    let mut allocations = 0;
+    let mut written = 0;
+    with_writer(
+        |i| process_stub_state(adapter_state(i)),
+        || {
+            allocations = bench("GetKeys", |i| {
+                let mut keys = 0u32;
+                // Safety: `BUTTONS` is a union of a `u32` and its bitfields
+                unsafe { GetKeys((i % 4) as i32, (&mut keys as *mut u32).cast()) };
+                written |= black_box(keys);
+            });
+        },
+    );
+    assert_ne!(0, written, "GetKeys should see the connected controllers");
+    allocations
+}
+
+fn main() {
+    let allocations = bench_samples() + bench_mapping() + bench_get_keys();
+    assert_eq!(
+        0, allocations,
+        "the paths used by GetKeys should not allocate"
+    );
+}
@@ -1,12 +1,21 @@
+use crate::{config::StickConfig, health::AdapterHealth};
 use rusb::{DeviceHandle, GlobalContext};
-use std::{convert::TryFrom, fmt::Debug, time::Duration};
+use std::{
+    convert::TryFrom,
+    fmt::Debug,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 const ENDPOINT_IN: u8 = 0x81;
 const ENDPOINT_OUT: u8 = 0x02;
 const READ_LEN: usize = 37;
+/// The first byte of a report with the states of the controllers.
+const REPORT_HEADER: u8 = 0x21;
 
 pub struct GcAdapter {
     handle: DeviceHandle<GlobalContext>,
+    health: Mutex<AdapterHealth>,
 }
 
 impl Debug for GcAdapter {
@@ -45,19 +54,45 @@ impl GcAdapter {
         handle.claim_interface(0)?;
         handle.write_interrupt(ENDPOINT_OUT, &[0x13], Duration::from_millis(16))?;
 
-        Ok(GcAdapter { handle })
+        Ok(GcAdapter {
+            handle,
+            health: Mutex::new(AdapterHealth::new()),
+        })
     }
 
-    pub fn read(&self) -> rusb::Result<[u8; READ_LEN]> {
-        let mut buf = [0; READ_LEN];
+    /// Read a report from the adapter. Returns `None` if the adapter did not send a full and valid
+    /// report in time, in which case the previous report should be kept.
+    pub fn read(&self) -> rusb::Result<Option<[u8; READ_LEN]>> {
+        self.read_timeout(Duration::from_millis(16))
+    }
 
-        match self
-            .handle
-            .read_interrupt(ENDPOINT_IN, &mut buf, Duration::from_millis(16))
-        {
-            Ok(_) | Err(rusb::Error::Timeout) => Ok(buf),
-            Err(e) => Err(e),
+    /// Read a report from the adapter, waiting at most `timeout` for it.
+    pub fn read_timeout(&self, timeout: Duration) -> rusb::Result<Option<[u8; READ_LEN]>> {
+        let mut buf = [0; READ_LEN];
+        let result = self.handle.read_interrupt(ENDPOINT_IN, &mut buf, timeout);
+
+        let mut health = self.health.lock().unwrap();
+        match result {
+            Ok(len) if len < READ_LEN => health.record_short_read(),
+            Ok(_) if buf[0] != REPORT_HEADER => health.record_invalid_report(),
+            Ok(_) => {
+                health.record_read(Instant::now());
+                return Ok(Some(buf));
+            }
+            Err(e) => {
+                health.record_error(e);
+                if e != rusb::Error::Timeout {
+                    return Err(e);
+                }
+            }
         }
+
+        Ok(None)
+    }
+
+    /// Get the counters and statistics of the reads from the adapter.
+    pub fn health(&self) -> AdapterHealth {
+        self.health.lock().unwrap().clone()
     }
 
     pub fn set_rumble(&self, strengths: [u8; 4]) -> rusb::Result<()> {
@@ -96,6 +131,15 @@ impl AdapterState {
         }
     }
 
+    pub fn controller_state_mut(&mut self, channel: Channel) -> &mut ControllerState {
+        match channel {
+            Channel::One => &mut self.controller_0,
+            Channel::Two => &mut self.controller_1,
+            Channel::Three => &mut self.controller_2,
+            Channel::Four => &mut self.controller_3,
+        }
+    }
+
     pub fn any_connected(&self) -> bool {
         self.controller_0.is_connected()
             || self.controller_1.is_connected()
@@ -104,6 +148,12 @@ impl AdapterState {
     }
 }
 
+impl Default for AdapterState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<[u8; READ_LEN]> for AdapterState {
     fn from(bytes: [u8; READ_LEN]) -> Self {
         let controller_0 = ControllerState::from(&bytes[1..]);
@@ -120,6 +170,50 @@ impl From<[u8; READ_LEN]> for AdapterState {
     }
 }
 
+/// The neutral position of an analog stick.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StickOrigin {
+    pub x: u8,
+    pub y: u8,
+}
+
+impl StickOrigin {
+    pub const CENTER: StickOrigin = StickOrigin { x: 128, y: 128 };
+
+    fn offset(&self, x: u8, y: u8) -> (i8, i8) {
+        (
+            (x as i16 - self.x as i16).clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+            (y as i16 - self.y as i16).clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+        )
+    }
+
+    /// Rotate, swap and invert the axes of a stick position around the origin.
+    fn transform(&self, x: u8, y: u8, cfg: &StickConfig) -> (u8, u8) {
+        if !cfg.invert_x && !cfg.invert_y && !cfg.swap_axes && cfg.rotation_degrees == 0.0 {
+            return (x, y);
+        }
+
+        let (dx, dy) = (x as f32 - self.x as f32, y as f32 - self.y as f32);
+
+        let (sin, cos) = cfg.rotation_degrees.to_radians().sin_cos();
+        let (dx, dy) = (dx * cos - dy * sin, dx * sin + dy * cos);
+        let (dx, dy) = if cfg.swap_axes { (dy, dx) } else { (dx, dy) };
+        let dx = if cfg.invert_x { -dx } else { dx };
+        let dy = if cfg.invert_y { -dy } else { dy };
+
+        (
+            (self.x as f32 + dx).round().clamp(0.0, 255.0) as u8,
+            (self.y as f32 + dy).round().clamp(0.0, 255.0) as u8,
+        )
+    }
+}
+
+impl Default for StickOrigin {
+    fn default() -> Self {
+        Self::CENTER
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
 pub struct ControllerState {
     pub status: u8,
@@ -145,6 +239,18 @@ pub struct ControllerState {
     pub substick_y: u8,
     pub trigger_left: u8,
     pub trigger_right: u8,
+
+    pub stick_origin: StickOrigin,
+    pub substick_origin: StickOrigin,
+    /// Deadzone learned from the noise of the control stick, if it has an adaptive deadzone.
+    pub stick_deadzone: Option<u8>,
+    /// Deadzone learned from the noise of the C-stick, if it has an adaptive deadzone.
+    pub substick_deadzone: Option<u8>,
+    /// N64 buttons pressed by tap, hold and double-tap bindings.
+    pub bound_buttons: u32,
+    /// GC buttons that are held with turbo, and pressed and released by it, in the order of
+    /// `GcButton::ALL`.
+    pub turbo_buttons: u16,
 }
 
 impl ControllerState {
@@ -169,14 +275,40 @@ impl ControllerState {
             substick_y: 0,
             trigger_left: 0,
             trigger_right: 0,
+            stick_origin: StickOrigin::CENTER,
+            substick_origin: StickOrigin::CENTER,
+            stick_deadzone: None,
+            substick_deadzone: None,
+            bound_buttons: 0,
+            turbo_buttons: 0,
         }
     }
 
+    /// Get the position of the control stick relative to its origin.
+    pub fn stick(&self) -> (i8, i8) {
+        self.stick_origin.offset(self.stick_x, self.stick_y)
+    }
+
+    /// Get the position of the C-stick relative to its origin.
+    pub fn substick(&self) -> (i8, i8) {
+        self.substick_origin
+            .offset(self.substick_x, self.substick_y)
+    }
+
+    /// Apply the axis transformations for the sticks (rotation, swapping and inverting).
+    pub fn transform_sticks(&mut self, stick: &StickConfig, substick: &StickConfig) {
+        (self.stick_x, self.stick_y) =
+            self.stick_origin
+                .transform(self.stick_x, self.stick_y, stick);
+        (self.substick_x, self.substick_y) =
+            self.substick_origin
+                .transform(self.substick_x, self.substick_y, substick);
+    }
+
     pub fn stick_with_deadzone(&self, deadzone: u8, sensitivity: u8) -> (i8, i8) {
         const STICK_MAX: i32 = i8::MAX as i32;
 
-        let x = self.stick_x.wrapping_add(128) as i8;
-        let y = self.stick_y.wrapping_add(128) as i8;
+        let (x, y) = self.stick();
 
         // Convert cartesian coordinates to polar coordinates (radius)
         let radius = ((x as f32).powi(2) + (y as f32).powi(2)).sqrt();
@@ -209,8 +341,7 @@ impl ControllerState {
     }
 
     pub fn substick_with_deadzone(&self, deadzone: u8) -> (i8, i8) {
-        let x = self.substick_x.wrapping_add(128) as i8;
-        let y = self.substick_y.wrapping_add(128) as i8;
+        let (x, y) = self.substick();
 
         let x = if x.unsigned_abs() < deadzone { 0 } else { x };
 
@@ -219,6 +350,12 @@ impl ControllerState {
         (x, y)
     }
 
+    /// Check if L, R and Start are held, which resets the origin of the sticks like on an N64
+    /// controller.
+    pub fn is_reset_held(&self) -> bool {
+        self.l && self.r && self.start
+    }
+
     pub fn is_connected(&self) -> bool {
         // 0x10 = Normal
         // 0x20 = Wavebird
@@ -256,6 +393,13 @@ impl From<&[u8]> for ControllerState {
             substick_y,
             trigger_left,
             trigger_right,
+
+            stick_origin: StickOrigin::CENTER,
+            substick_origin: StickOrigin::CENTER,
+            stick_deadzone: None,
+            substick_deadzone: None,
+            bound_buttons: 0,
+            turbo_buttons: 0,
         }
     }
 }
@@ -326,4 +470,49 @@ mod tests {
         assert_eq!(0x4, state.controller_3.status);
         // TODO: Write more assertions
     }
+
+    #[test]
+    fn test_stick_origin() {
+        let mut state = ControllerState {
+            stick_x: 250,
+            stick_y: 3,
+            ..ControllerState::new()
+        };
+        assert_eq!((122, -125), state.stick());
+
+        state.stick_origin = StickOrigin { x: 120, y: 134 };
+        assert_eq!((127, -128), state.stick());
+
+        state.stick_x = 120;
+        state.stick_y = 134;
+        assert_eq!((0, 0), state.stick());
+        assert_eq!((0, 0), state.stick_with_deadzone(0, 180));
+    }
+
+    #[test]
+    fn test_transform_sticks() {
+        let mut state = ControllerState {
+            stick_x: 148,
+            stick_y: 118,
+            substick_origin: StickOrigin { x: 130, y: 126 },
+            substick_x: 140,
+            substick_y: 126,
+            ..ControllerState::new()
+        };
+
+        let stick = StickConfig {
+            invert_y: true,
+            swap_axes: true,
+            ..Default::default()
+        };
+        let substick = StickConfig {
+            rotation_degrees: 90.0,
+            invert_x: true,
+            ..Default::default()
+        };
+        state.transform_sticks(&stick, &substick);
+
+        assert_eq!((-10, -20), state.stick());
+        assert_eq!((0, 10), state.substick());
+    }
 }
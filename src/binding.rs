@@ -0,0 +1,182 @@
+use crate::{
+    adapter::{AdapterState, Channel},
+    config::{Config, N64Button, TapBinding},
+};
+use std::time::{Duration, Instant};
+
+/// How long a tap or double-tap presses its N64 button at least, so that the game sees the press
+/// even when the GC button was only pressed for a moment.
+const TAP_TIME: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Step {
+    Idle,
+    Pressed {
+        since: Instant,
+    },
+    Held,
+    /// Released after a tap, waiting to see if the button is pressed again for a double-tap.
+    Released {
+        at: Instant,
+    },
+    DoubleTapped,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct BindingState {
+    step: Step,
+    pulse: Option<(N64Button, Instant)>,
+}
+
+impl BindingState {
+    const fn new() -> Self {
+        Self {
+            step: Step::Idle,
+            pulse: None,
+        }
+    }
+
+    /// Advance the binding, and get the bit pattern of the N64 buttons it presses.
+    fn update(&mut self, pressed: bool, binding: &TapBinding, now: Instant) -> u32 {
+        let hold_time = Duration::from_millis(binding.hold_ms as u64);
+        let double_tap_time = Duration::from_millis(binding.double_tap_ms as u64);
+
+        if let Step::Released { at } = self.step {
+            if now - at >= double_tap_time {
+                self.pulse = Some((binding.tap, now + TAP_TIME));
+                self.step = Step::Idle;
+            }
+        }
+
+        self.step = match (self.step, pressed) {
+            (Step::Idle, true) => Step::Pressed { since: now },
+            (Step::Pressed { since }, true) => match binding.hold {
+                Some(_) if now - since >= hold_time => Step::Held,
+                _ => Step::Pressed { since },
+            },
+            (Step::Pressed { .. }, false) => match binding.double_tap {
+                Some(_) => Step::Released { at: now },
+                None => {
+                    self.pulse = Some((binding.tap, now + TAP_TIME));
+                    Step::Idle
+                }
+            },
+            (Step::Released { .. }, true) => {
+                if let Some(double_tap) = binding.double_tap {
+                    self.pulse = Some((double_tap, now + TAP_TIME));
+                }
+                Step::DoubleTapped
+            }
+            (Step::Held | Step::DoubleTapped, false) => Step::Idle,
+            (step, _) => step,
+        };
+
+        let held = match self.step {
+            Step::Held => binding.hold,
+            Step::DoubleTapped => binding.double_tap,
+            _ => None,
+        };
+        let pulse = match self.pulse {
+            Some((button, until)) if now < until => Some(button),
+            _ => {
+                self.pulse = None;
+                None
+            }
+        };
+
+        held.map_or(0, |b| b.bit_pattern()) | pulse.map_or(0, |b| b.bit_pattern())
+    }
+}
+
+/// Tap, hold and double-tap bindings for each controller.
+///
+/// A bound GC button no longer presses the N64 button it is mapped to. Instead, a tap presses the
+/// tap binding shortly when the button is released (or when the double-tap window has passed),
+/// holding the button presses the hold binding, and pressing the button again right after a tap
+/// presses the double-tap binding. The bound N64 buttons are stored in the controller state, since
+/// the timing is measured on the timeline of the adapter thread.
+#[derive(Debug)]
+pub struct TapBindings {
+    channels: [Vec<BindingState>; 4],
+}
+
+impl TapBindings {
+    pub const fn new() -> Self {
+        Self {
+            channels: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+        }
+    }
+
+    pub fn update(&mut self, state: &mut AdapterState, cfg: &Config, now: Instant) {
+        for (i, states) in self.channels.iter_mut().enumerate() {
+            let channel = Channel::try_from(i).unwrap();
+            let bindings = &cfg.channel(channel).tap_bindings;
+            let s = state.controller_state_mut(channel);
+            if !s.is_connected() {
+                states.clear();
+                continue;
+            }
+
+            states.resize(bindings.len(), BindingState::new());
+            for (binding, binding_state) in bindings.iter().zip(states) {
+                let pressed = binding.button.is_pressed(s);
+                s.bound_buttons |= binding_state.update(pressed, binding, now);
+                binding.button.set_pressed(s, false);
+            }
+        }
+    }
+}
+
+impl Default for TapBindings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GcButton;
+
+    #[test]
+    fn test_tap_binding() {
+        let binding = TapBinding {
+            button: GcButton::X,
+            tap: N64Button::CRight,
+            hold: Some(N64Button::CUp),
+            double_tap: Some(N64Button::CDown),
+            hold_ms: 250,
+            double_tap_ms: 200,
+        };
+        let tap = N64Button::CRight.bit_pattern();
+        let hold = N64Button::CUp.bit_pattern();
+        let double_tap = N64Button::CDown.bit_pattern();
+        let mut state = BindingState::new();
+        let start = Instant::now();
+        let mut update =
+            |pressed, ms| state.update(pressed, &binding, start + Duration::from_millis(ms));
+
+        // Tap, fired when the double-tap window has passed
+        assert_eq!(0, update(true, 0));
+        assert_eq!(0, update(false, 100));
+        assert_eq!(0, update(false, 250));
+        assert_eq!(tap, update(false, 300));
+        assert_eq!(tap, update(false, 340));
+        assert_eq!(0, update(false, 360));
+
+        // Hold
+        assert_eq!(0, update(true, 1000));
+        assert_eq!(0, update(true, 1200));
+        assert_eq!(hold, update(true, 1250));
+        assert_eq!(hold, update(true, 2000));
+        assert_eq!(0, update(false, 2001));
+
+        // Double-tap, pressed for at least the tap time
+        assert_eq!(0, update(true, 3000));
+        assert_eq!(0, update(false, 3050));
+        assert_eq!(double_tap, update(true, 3100));
+        assert_eq!(double_tap, update(false, 3110));
+        assert_eq!(0, update(false, 3150));
+        assert_eq!(0, update(false, 3500));
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Set in `middle` when the middle buffer holds a value that the reader has not seen yet.
+const NEW: u8 = 0b100;
+
+/// A triple buffer, which passes values from one writer thread to one reader thread without
+/// locking, so that neither side ever waits for the other.
+///
+/// The writer writes to its back buffer and swaps it with the middle buffer, and the reader swaps
+/// its front buffer with the middle buffer when the middle buffer holds a newer value. Each buffer
+/// is therefore only accessed by one side at a time.
+#[derive(Debug)]
+pub struct TripleBuffer<T> {
+    buffers: [UnsafeCell<T>; 3],
+    middle: AtomicU8,
+    /// Only accessed by the writer.
+    back: UnsafeCell<u8>,
+    /// Only accessed by the reader.
+    front: UnsafeCell<u8>,
+}
+
+// Safety: the buffers are handed between the writer and the reader through `middle`, and are only
+// accessed by the side that owns them.
+unsafe impl<T: Send> Sync for TripleBuffer<T> {}
+
+impl<T: Copy> TripleBuffer<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            buffers: [
+                UnsafeCell::new(value),
+                UnsafeCell::new(value),
+                UnsafeCell::new(value),
+            ],
+            middle: AtomicU8::new(1),
+            back: UnsafeCell::new(0),
+            front: UnsafeCell::new(2),
+        }
+    }
+
+    /// Publish a new value.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may publish at a time.
+    pub unsafe fn publish(&self, value: T) {
+        let back = &mut *self.back.get();
+        *self.buffers[*back as usize].get() = value;
+        *back = self.middle.swap(*back | NEW, Ordering::AcqRel) & !NEW;
+    }
+
+    /// Get the newest published value.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may read at a time.
+    pub unsafe fn read(&self) -> T {
+        let front = &mut *self.front.get();
+        if self.middle.load(Ordering::Relaxed) & NEW != 0 {
+            *front = self.middle.swap(*front, Ordering::AcqRel) & !NEW;
+        }
+
+        *self.buffers[*front as usize].get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_triple_buffer() {
+        let buffer = TripleBuffer::new(0);
+        unsafe {
+            assert_eq!(0, buffer.read());
+            buffer.publish(1);
+            buffer.publish(2);
+            assert_eq!(2, buffer.read());
+            assert_eq!(2, buffer.read());
+            buffer.publish(3);
+            assert_eq!(3, buffer.read());
+        }
+
+        // The reader never sees an older value, or a value that is being written
+        static BUFFER: TripleBuffer<[u64; 8]> = TripleBuffer::new([0; 8]);
+        let writer = thread::spawn(|| {
+            for i in 1..=100_000 {
+                unsafe { BUFFER.publish([i; 8]) };
+            }
+        });
+
+        let mut last = 0;
+        while last < 100_000 {
+            let value = unsafe { BUFFER.read() };
+            assert!(value.iter().all(|&v| v == value[0]));
+            assert!(value[0] >= last);
+            last = value[0];
+        }
+        writer.join().unwrap();
+    }
+}
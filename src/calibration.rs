@@ -0,0 +1,388 @@
+use crate::{
+    adapter::{AdapterState, Channel},
+    config::Chord,
+    debug::M64Message,
+    stick::{notches_valid, Notches},
+};
+use std::{
+    f32::consts::FRAC_PI_4,
+    time::{Duration, Instant},
+};
+
+/// How far the stick has to reach in every direction while rotating it around the gate.
+const MIN_ROTATION_RADIUS: f32 = 50.0;
+/// The stick is considered to be in a notch when it is at least this far out, relative to how far
+/// the stick reached in that direction while rotating it.
+const NOTCH_MIN_RADIUS: f32 = 0.85;
+/// How long the stick has to be held still in a notch before the notch is recorded.
+const NOTCH_HOLD_TIME: Duration = Duration::from_millis(300);
+/// How far the stick can move while it is held in a notch.
+const NOTCH_TOLERANCE: i16 = 2;
+/// The stick is considered released when it is closer to the centre than this.
+const RELEASED_RADIUS: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Hold {
+    notch: usize,
+    position: [i8; 2],
+    since: Instant,
+    recorded: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Idle,
+    /// The player rotates the stick around the gate, to find how far the stick reaches in each
+    /// direction.
+    Rotate {
+        max_radius: [f32; 8],
+    },
+    /// The player presses the stick into each of the notches.
+    Notches {
+        max_radius: [f32; 8],
+        found: [Option<[i8; 2]>; 8],
+        hold: Option<Hold>,
+    },
+}
+
+/// Gate calibration for the control stick.
+///
+/// When the calibration hotkey is pressed the player first rotates the stick around the gate, and
+/// then presses the stick into each notch. When all notches have been recorded, the notches are
+/// returned by `update` so that they can be used to remap the stick.
+#[derive(Debug)]
+pub struct Calibrator {
+    steps: [Step; 4],
+    hotkey_held: [bool; 4],
+}
+
+impl Calibrator {
+    pub const fn new() -> Self {
+        Self {
+            steps: [Step::Idle; 4],
+            hotkey_held: [false; 4],
+        }
+    }
+
+    /// Advance the calibration with a new state from the adapter. Returns the notches for a
+    /// channel when its calibration is finished.
+    pub fn update(
+        &mut self,
+        state: &AdapterState,
+        hotkey: &Chord,
+        now: Instant,
+    ) -> Option<(Channel, Notches)> {
+        let mut finished = None;
+
+        for i in 0..4 {
+            let channel = Channel::try_from(i).unwrap();
+            let s = state.controller_state(channel);
+
+            let hotkey_held = s.is_connected() && hotkey.is_held(&s);
+            let hotkey_pressed = hotkey_held && !self.hotkey_held[i];
+            self.hotkey_held[i] = hotkey_held;
+
+            if hotkey_pressed {
+                self.steps[i] = match self.steps[i] {
+                    Step::Idle => {
+                        debug_print!(
+                            M64Message::Status,
+                            "Calibrating control stick for controller {}: rotate the stick around the gate a few times, then let go of it",
+                            i + 1
+                        );
+                        Step::Rotate {
+                            max_radius: [0.0; 8],
+                        }
+                    }
+                    _ => {
+                        debug_print!(
+                            M64Message::Status,
+                            "Calibration cancelled for controller {}",
+                            i + 1
+                        );
+                        Step::Idle
+                    }
+                };
+                continue;
+            }
+
+            if !s.is_connected() {
+                self.steps[i] = Step::Idle;
+                continue;
+            }
+
+            let (x, y) = s.stick();
+            let radius = (x as f32).hypot(y as f32);
+            let notch = ((y as f32).atan2(x as f32) / FRAC_PI_4).round() as i32;
+            let notch = notch.rem_euclid(8) as usize;
+
+            match &mut self.steps[i] {
+                Step::Idle => (),
+                Step::Rotate { max_radius } => {
+                    max_radius[notch] = max_radius[notch].max(radius);
+
+                    if radius < RELEASED_RADIUS
+                        && max_radius.iter().all(|&r| r >= MIN_ROTATION_RADIUS)
+                    {
+                        debug_print!(
+                            M64Message::Status,
+                            "Now press the stick firmly into each of the 8 notches, one at a time"
+                        );
+                        self.steps[i] = Step::Notches {
+                            max_radius: *max_radius,
+                            found: [None; 8],
+                            hold: None,
+                        };
+                    }
+                }
+                Step::Notches {
+                    max_radius,
+                    found,
+                    hold,
+                } => {
+                    if radius < NOTCH_MIN_RADIUS * max_radius[notch] {
+                        *hold = None;
+                        continue;
+                    }
+
+                    let position = [x, y];
+                    match hold {
+                        Some(h)
+                            if h.notch == notch
+                                && (h.position[0] as i16 - x as i16).abs() <= NOTCH_TOLERANCE
+                                && (h.position[1] as i16 - y as i16).abs() <= NOTCH_TOLERANCE =>
+                        {
+                            if h.recorded || now.duration_since(h.since) < NOTCH_HOLD_TIME {
+                                continue;
+                            }
+
+                            h.recorded = true;
+                            found[notch] = Some(position);
+                            debug_print!(
+                                M64Message::Status,
+                                "Recorded notch {} at ({}, {})",
+                                notch + 1,
+                                x,
+                                y
+                            );
+                        }
+                        _ => {
+                            *hold = Some(Hold {
+                                notch,
+                                position,
+                                since: now,
+                                recorded: false,
+                            });
+                            continue;
+                        }
+                    }
+
+                    if found.iter().any(Option::is_none) {
+                        continue;
+                    }
+
+                    let notches = found.map(Option::unwrap);
+                    if notches_valid(&notches) {
+                        debug_print!(
+                            M64Message::Status,
+                            "Calibration finished for controller {}",
+                            i + 1
+                        );
+                        self.steps[i] = Step::Idle;
+                        finished = Some((channel, notches));
+                    } else {
+                        debug_print!(
+                            M64Message::Status,
+                            "The recorded notches are not in order around the gate, press the stick into each notch again"
+                        );
+                        *found = [None; 8];
+                    }
+                }
+            }
+        }
+
+        finished
+    }
+}
+
+impl Default for Calibrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{adapter::ControllerState, config::GcButton};
+
+    const NOTCHES: Notches = [
+        [75, 0],
+        [53, 53],
+        [0, 75],
+        [-53, 53],
+        [-75, 0],
+        [-53, -53],
+        [0, -75],
+        [53, -53],
+    ];
+
+    /// Feeds states of the first controller to a calibrator, 10 ms apart.
+    struct Driver {
+        calibrator: Calibrator,
+        hotkey: Chord,
+        now: Instant,
+    }
+
+    impl Driver {
+        fn new() -> Self {
+            Self {
+                calibrator: Calibrator::new(),
+                hotkey: Chord(vec![GcButton::Z, GcButton::DPadUp]),
+                now: Instant::now(),
+            }
+        }
+
+        fn update(&mut self, [x, y]: [i8; 2], hotkey: bool) -> Option<(usize, Notches)> {
+            let mut state = AdapterState::new();
+            state.controller_0 = ControllerState {
+                status: 0x10,
+                stick_x: (128 + x as i16) as u8,
+                stick_y: (128 + y as i16) as u8,
+                z: hotkey,
+                up: hotkey,
+                ..ControllerState::new()
+            };
+            self.now += Duration::from_millis(10);
+            self.calibrator
+                .update(&state, &self.hotkey, self.now)
+                .map(|(channel, notches)| (channel as usize, notches))
+        }
+
+        fn press_hotkey(&mut self) {
+            self.update([0, 0], true);
+            self.update([0, 0], false);
+        }
+
+        fn rotate(&mut self) {
+            for degrees in (0..720).step_by(5) {
+                let (sin, cos) = (degrees as f32).to_radians().sin_cos();
+                self.update([(70.0 * cos) as i8, (70.0 * sin) as i8], false);
+            }
+            self.update([0, 0], false);
+        }
+
+        /// Hold the stick at `position` for `ms`, and let go of it.
+        fn hold(&mut self, position: [i8; 2], ms: u32) -> Option<(usize, Notches)> {
+            let mut finished = None;
+            for _ in 0..ms / 10 {
+                finished = finished.or(self.update(position, false));
+            }
+            self.update([0, 0], false);
+            finished
+        }
+    }
+
+    #[test]
+    fn test_calibration() {
+        let mut driver = Driver::new();
+        driver.press_hotkey();
+        driver.rotate();
+        assert!(matches!(driver.calibrator.steps[0], Step::Notches { .. }));
+
+        for &notch in &NOTCHES[..7] {
+            assert_eq!(None, driver.hold(notch, 400));
+        }
+        // Let go of too early
+        assert_eq!(None, driver.hold(NOTCHES[7], 200));
+
+        // Moving within the tolerance keeps the hold going, and the last position is recorded
+        driver.update([53, -53], false);
+        driver.update([54, -52], false);
+        driver.update([52, -54], false);
+        let finished = driver.hold([53, -54], 300);
+
+        let mut notches = NOTCHES;
+        notches[7] = [53, -54];
+        assert_eq!(Some((0, notches)), finished);
+        assert!(matches!(driver.calibrator.steps[0], Step::Idle));
+    }
+
+    #[test]
+    fn test_notch_tolerance() {
+        let mut driver = Driver::new();
+        driver.press_hotkey();
+        driver.rotate();
+
+        // Moving further than the tolerance starts the hold over
+        for _ in 0..20 {
+            driver.update([75, 0], false);
+            driver.update([75, 4], false);
+        }
+        let Step::Notches { found, .. } = driver.calibrator.steps[0] else {
+            panic!("the calibration should be waiting for the notches");
+        };
+        assert_eq!(None, found[0]);
+    }
+
+    #[test]
+    fn test_calibration_cancel() {
+        let mut driver = Driver::new();
+        driver.press_hotkey();
+        driver.rotate();
+        driver.press_hotkey();
+        assert!(matches!(driver.calibrator.steps[0], Step::Idle));
+        for notch in NOTCHES {
+            assert_eq!(None, driver.hold(notch, 400));
+        }
+
+        // Disconnecting the controller also cancels the calibration
+        driver.press_hotkey();
+        driver.rotate();
+        driver
+            .calibrator
+            .update(&AdapterState::new(), &driver.hotkey, driver.now);
+        assert!(matches!(driver.calibrator.steps[0], Step::Idle));
+    }
+
+    #[test]
+    fn test_rotation_required() {
+        let mut driver = Driver::new();
+        driver.press_hotkey();
+
+        // Only half of the gate is reached
+        for degrees in (0..180).step_by(5) {
+            let (sin, cos) = (degrees as f32).to_radians().sin_cos();
+            driver.update([(70.0 * cos) as i8, (70.0 * sin) as i8], false);
+        }
+        driver.update([0, 0], false);
+        assert!(matches!(driver.calibrator.steps[0], Step::Rotate { .. }));
+    }
+
+    #[test]
+    fn test_invalid_notches_retry() {
+        let mut driver = Driver::new();
+        driver.press_hotkey();
+        driver.rotate();
+
+        // Notches that go around the gate the wrong way, which can not be recorded by moving the
+        // stick, but are checked before they are used
+        let Step::Notches { found, .. } = &mut driver.calibrator.steps[0] else {
+            panic!("the calibration should be waiting for the notches");
+        };
+        for (i, f) in found.iter_mut().enumerate().skip(1) {
+            *f = Some(NOTCHES[8 - i]);
+        }
+
+        assert_eq!(None, driver.hold(NOTCHES[0], 400));
+        let Step::Notches { found, .. } = driver.calibrator.steps[0] else {
+            panic!("the calibration should start the notches over");
+        };
+        assert_eq!([None; 8], found);
+
+        for notch in NOTCHES {
+            driver.hold(notch, 400);
+        }
+        assert!(matches!(driver.calibrator.steps[0], Step::Idle));
+    }
+}
@@ -1,3 +1,7 @@
+use crate::{
+    adapter::{Channel, ControllerState},
+    stick::{Notches, Octagon},
+};
 use serde::{Deserialize, Serialize};
 use std::{
     fs::File,
@@ -9,12 +13,42 @@ use std::{
 pub struct Config {
     pub control_stick_deadzone: u8,
     pub control_stick_sensitivity: u8,
+    #[serde(default)]
+    pub control_stick_gate: GateProfile,
     pub c_stick_deadzone: u8,
+    #[serde(default)]
+    pub c_stick_release_deadzone: Option<u8>,
+    #[serde(default)]
+    pub c_stick_mode: CStickMode,
+    #[serde(default = "default_c_stick_diagonal_width")]
+    pub c_stick_diagonal_width: f32,
+    #[serde(default)]
+    pub stick_sampling: StickSampling,
+    /// Read all controllers at once per frame.
+    #[serde(default)]
+    pub frame_snapshot: bool,
+    #[serde(default)]
+    pub polling_mode: PollingMode,
+    /// How long `GetKeys` waits for the adapter in synchronous mode.
+    #[serde(default = "default_sync_poll_timeout_ms")]
+    pub sync_poll_timeout_ms: u8,
+    /// How many reports in a row a controller has to be reported as connected or disconnected
+    /// before it is considered to be.
+    #[serde(default = "default_connection_debounce_frames")]
+    pub connection_debounce_frames: u8,
     pub trigger_threshold: u8,
     pub controller_mapping: ControllerMapping,
+    #[serde(default)]
+    pub hotkeys: Hotkeys,
+    #[serde(default)]
+    pub channel: ChannelConfigs,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chords: Vec<ChordMapping>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ControllerMapping {
     pub a: N64Button,
     pub b: N64Button,
@@ -34,20 +68,438 @@ pub struct ControllerMapping {
     pub c_stick_up: N64Button,
 }
 
+impl ControllerMapping {
+    /// Get the bit pattern for the N64 buttons pressed by the digital GC buttons. L and R are not
+    /// included, since they are pressed by the analog triggers as well.
+    pub fn bit_pattern(&self, state: &ControllerState) -> u32 {
+        [
+            GcButton::A,
+            GcButton::B,
+            GcButton::X,
+            GcButton::Y,
+            GcButton::Start,
+            GcButton::Z,
+            GcButton::DPadLeft,
+            GcButton::DPadRight,
+            GcButton::DPadDown,
+            GcButton::DPadUp,
+        ]
+        .iter()
+        .filter(|b| b.is_pressed(state))
+        .fold(0, |bits, &b| bits | self.get(b).bit_pattern())
+    }
+
+    /// Get the N64 button a GC button is mapped to.
+    pub fn get(&self, button: GcButton) -> N64Button {
+        match button {
+            GcButton::A => self.a,
+            GcButton::B => self.b,
+            GcButton::X => self.x,
+            GcButton::Y => self.y,
+            GcButton::Start => self.start,
+            GcButton::Z => self.z,
+            GcButton::L => self.l,
+            GcButton::R => self.r,
+            GcButton::DPadLeft => self.d_pad_left,
+            GcButton::DPadRight => self.d_pad_right,
+            GcButton::DPadDown => self.d_pad_down,
+            GcButton::DPadUp => self.d_pad_up,
+        }
+    }
+}
+
+/// GC buttons that press an N64 button when held at the same time, instead of their own mapping.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChordMapping {
+    pub buttons: Chord,
+    pub press: N64Button,
+}
+
+/// An alternate mapping that is used while the modifier button is held.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Layer {
+    pub modifier: GcButton,
+    pub mapping: ControllerMapping,
+}
+
+fn default_c_stick_diagonal_width() -> f32 {
+    20.0
+}
+
+fn default_sync_poll_timeout_ms() -> u8 {
+    2
+}
+
+fn default_connection_debounce_frames() -> u8 {
+    3
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Hotkeys {
+    /// Start or cancel the gate calibration for the control stick.
+    pub calibrate: Chord,
+    /// Turn turbo on or off for the N64 button of a GC button pressed while this is held.
+    pub turbo: Chord,
+    /// Start or stop recording a macro. Empty by default, since saving a recording rewrites the
+    /// config file.
+    pub record_macro: Chord,
+    /// Play the recorded macro.
+    pub play_macro: Chord,
+    /// Print the measured input latency of each controller.
+    pub report_latency: Chord,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self {
+            calibrate: Chord(vec![GcButton::Z, GcButton::DPadUp]),
+            turbo: Chord(vec![GcButton::Z, GcButton::DPadDown]),
+            record_macro: Chord(vec![]),
+            play_macro: Chord(vec![GcButton::Z, GcButton::DPadRight]),
+            report_latency: Chord(vec![GcButton::Z, GcButton::Start]),
+        }
+    }
+}
+
+/// Configuration that only applies to the controller connected to one of the adapter's ports.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChannelConfig {
+    /// The measured notches of the control stick, as recorded by the gate calibration.
+    pub control_stick_notches: Option<Notches>,
+    /// Press the N64 D-pad when the control stick is pushed further than this from its origin, in
+    /// raw GC units (0 to 127) before the deadzone and sensitivity are applied.
+    pub control_stick_to_d_pad: Option<u8>,
+    /// Press the N64 D-pad instead of the C-buttons when the C-stick is pushed further than this
+    /// from its origin, in raw GC units (0 to 127).
+    pub c_stick_to_d_pad: Option<u8>,
+    /// Move the control stick this far when the GC D-pad is pressed, in N64 units (0 to 127).
+    pub d_pad_to_stick: Option<u8>,
+    /// Delay the input by this many frames.
+    pub input_delay_frames: u8,
+    /// N64 buttons that are latched on by one press and released by the next.
+    pub toggle_buttons: Vec<N64Button>,
+    /// N64 buttons that are latched on by one press and released after the next other button.
+    pub sticky_buttons: Vec<N64Button>,
+    /// Zones where the angle of the control stick snaps to a fixed angle.
+    pub snap_zones: Vec<SnapZone>,
+    pub control_stick: StickConfig,
+    pub c_stick: StickConfig,
+    pub trigger_left: TriggerConfig,
+    pub trigger_right: TriggerConfig,
+    pub walk_modifier: WalkModifier,
+    pub turbo: TurboConfig,
+    /// Buttons that set the control stick to a fixed position while held.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fixed_positions: Vec<FixedPosition>,
+    /// Buttons that press different N64 buttons when tapped, held or double-tapped.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tap_bindings: Vec<TapBinding>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub macros: Vec<Macro>,
+    /// The macro recorded with the record hotkey, played with the play hotkey.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recorded_macro: Vec<MacroStep>,
+}
+
+/// Configuration for one of the analog sticks of a controller.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StickConfig {
+    /// Follow slow drift of the stick's resting position, and size the deadzone from the noise
+    /// of the stick instead of using the configured deadzone.
+    pub adaptive_deadzone: bool,
+    /// How long (in milliseconds) to ignore movement to the opposite side after the stick is
+    /// quickly released, to suppress snapback. 0 disables snapback suppression.
+    pub snapback_window_ms: u16,
+    /// Time constant (in milliseconds) of the low-pass filter smoothing the stick. 0 disables
+    /// smoothing.
+    pub smoothing_ms: u16,
+    pub invert_x: bool,
+    pub invert_y: bool,
+    pub swap_axes: bool,
+    /// Rotation (in degrees, counter-clockwise) of the stick, applied before swapping and
+    /// inverting the axes.
+    pub rotation_degrees: f32,
+}
+
+/// Configuration for one of the analog triggers of a controller.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TriggerConfig {
+    /// N64 button pressed when the trigger is pressed past the threshold. If this is set, the
+    /// button in the controller mapping is only pressed by the digital click at the bottom of the
+    /// trigger.
+    pub light_press: Option<N64Button>,
+    /// Threshold for the analog value of the trigger (after calibration), instead of the global
+    /// trigger threshold.
+    pub threshold: Option<u8>,
+    /// How far below the threshold the trigger has to be let go of before it is released.
+    pub hysteresis: u8,
+    /// The analog value of the trigger when it is not pressed.
+    pub rest: u8,
+    /// The analog value of the trigger when it is fully pressed.
+    pub max: u8,
+}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        Self {
+            light_press: None,
+            threshold: None,
+            hysteresis: 0,
+            rest: 0,
+            max: u8::MAX,
+        }
+    }
+}
+
+impl TriggerConfig {
+    /// Scale the analog value of the trigger from the calibrated range to the full range.
+    pub fn depth(&self, value: u8) -> u8 {
+        let range = self.max.saturating_sub(self.rest).max(1) as u32;
+        let value = value.saturating_sub(self.rest) as u32;
+        (value * u8::MAX as u32 / range).min(u8::MAX as u32) as u8
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GcTrigger {
+    Left,
+    Right,
+}
+
+/// Scales down the control stick, for precise slow walking.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WalkModifier {
+    /// GC button that scales the stick by `scale` while held.
+    pub button: Option<GcButton>,
+    /// Analog trigger that scales the stick by how far it is pressed, reaching `scale` when fully
+    /// pressed.
+    pub trigger: Option<GcTrigger>,
+    pub scale: f32,
+}
+
+impl Default for WalkModifier {
+    fn default() -> Self {
+        Self {
+            button: None,
+            trigger: None,
+            scale: 0.5,
+        }
+    }
+}
+
+fn default_hold_ms() -> u16 {
+    250
+}
+
+fn default_double_tap_ms() -> u16 {
+    200
+}
+
+/// A GC button that presses one N64 button when tapped, and others when held or double-tapped.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TapBinding {
+    pub button: GcButton,
+    pub tap: N64Button,
+    #[serde(default)]
+    pub hold: Option<N64Button>,
+    #[serde(default)]
+    pub double_tap: Option<N64Button>,
+    /// How long the button has to be held for the hold binding.
+    #[serde(default = "default_hold_ms")]
+    pub hold_ms: u16,
+    /// How soon after being released the button has to be pressed again for a double-tap.
+    #[serde(default = "default_double_tap_ms")]
+    pub double_tap_ms: u16,
+}
+
+/// A sequence of N64 button and stick states that is played when the trigger is pressed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Macro {
+    pub trigger: Chord,
+    pub steps: Vec<MacroStep>,
+}
+
+/// One step of a macro, which is held for a number of frames or milliseconds. Without either, the
+/// step is held for one frame.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MacroStep {
+    #[serde(default)]
+    pub buttons: Vec<N64Button>,
+    #[serde(default)]
+    pub x: i8,
+    #[serde(default)]
+    pub y: i8,
+    /// How many times the input is read by the game, which is once per frame for most games.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frames: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ms: Option<u32>,
+}
+
+/// Buttons that are repeatedly pressed and released while held.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TurboConfig {
+    /// The N64 buttons with turbo. GC buttons mapped to these are repeated.
+    pub buttons: Vec<N64Button>,
+    /// How many times per second the buttons are pressed.
+    pub frequency: f32,
+    /// The fraction of each press that the buttons are held down.
+    pub duty_cycle: f32,
+}
+
+impl Default for TurboConfig {
+    fn default() -> Self {
+        Self {
+            buttons: Vec::new(),
+            frequency: 15.0,
+            duty_cycle: 0.5,
+        }
+    }
+}
+
+impl WalkModifier {
+    /// The factor to scale the control stick by for the given state.
+    pub fn factor(&self, state: &ControllerState, cfg: &ChannelConfig) -> f32 {
+        let scale = self.scale.clamp(0.0, 1.0);
+        let mut factor: f32 = 1.0;
+
+        if self.button.is_some_and(|b| b.is_pressed(state)) {
+            factor = factor.min(scale);
+        }
+
+        if let Some(trigger) = self.trigger {
+            let depth = match trigger {
+                GcTrigger::Left => cfg.trigger_left.depth(state.trigger_left),
+                GcTrigger::Right => cfg.trigger_right.depth(state.trigger_right),
+            };
+            let depth = depth as f32 / u8::MAX as f32;
+            factor = factor.min(1.0 - depth * (1.0 - scale));
+        }
+
+        factor
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StickDirection {
+    Right,
+    UpRight,
+    Up,
+    UpLeft,
+    Left,
+    DownLeft,
+    Down,
+    DownRight,
+}
+
+impl StickDirection {
+    /// The angle of the direction, in degrees counter-clockwise from the right.
+    pub fn angle(&self) -> f32 {
+        *self as u8 as f32 * 45.0
+    }
+}
+
+/// A button that sets the control stick to an exact N64 stick position while held.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FixedPosition {
+    pub button: GcButton,
+    /// Only set the position while the control stick is pushed in this direction (in 8-way).
+    #[serde(default)]
+    pub direction: Option<StickDirection>,
+    pub x: i8,
+    pub y: i8,
+    /// Rotate the position by the direction the control stick is pushed in, with the position
+    /// given for the stick pointing up.
+    #[serde(default)]
+    pub rotate: bool,
+}
+
+/// A zone of `width` degrees centered around `angle` degrees (counter-clockwise from the right),
+/// where the angle of the stick snaps to `angle`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SnapZone {
+    pub angle: f32,
+    pub width: f32,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ChannelConfigs {
+    #[serde(rename = "1", default)]
+    pub one: ChannelConfig,
+    #[serde(rename = "2", default)]
+    pub two: ChannelConfig,
+    #[serde(rename = "3", default)]
+    pub three: ChannelConfig,
+    #[serde(rename = "4", default)]
+    pub four: ChannelConfig,
+}
+
+/// The default configuration, which is written to the config file when it does not exist yet.
+const DEFAULT_CONFIG: &str = include_str!("../mupen64plus-input-gca.toml");
+
+impl Default for Config {
+    fn default() -> Self {
+        toml::from_str(DEFAULT_CONFIG).unwrap()
+    }
+}
+
 impl Config {
+    /// Get the mapping of the first layer whose modifier is held, together with the modifier, or
+    /// the regular mapping if no modifier is held.
+    pub fn mapping(&self, state: &ControllerState) -> (&ControllerMapping, Option<GcButton>) {
+        self.layers
+            .iter()
+            .find(|layer| layer.modifier.is_pressed(state))
+            .map_or((&self.controller_mapping, None), |layer| {
+                (&layer.mapping, Some(layer.modifier))
+            })
+    }
+
+    pub fn channel(&self, channel: Channel) -> &ChannelConfig {
+        match channel {
+            Channel::One => &self.channel.one,
+            Channel::Two => &self.channel.two,
+            Channel::Three => &self.channel.three,
+            Channel::Four => &self.channel.four,
+        }
+    }
+
+    pub fn channel_mut(&mut self, channel: Channel) -> &mut ChannelConfig {
+        match channel {
+            Channel::One => &mut self.channel.one,
+            Channel::Two => &mut self.channel.two,
+            Channel::Three => &mut self.channel.three,
+            Channel::Four => &mut self.channel.four,
+        }
+    }
+
     pub fn read_from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         let path = path.as_ref();
         let mut file = File::open(path)?;
         let mut string = String::new();
         file.read_to_string(&mut string)?;
-        let cfg = toml::from_str(&string).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let cfg = toml::from_str(&string).map_err(io::Error::other)?;
 
         Ok(cfg)
     }
 
+    /// Write the configuration to the given file.
+    ///
+    /// Note that this does not keep any comments in the file.
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = toml::to_string(self).map_err(io::Error::other)?;
+        File::create(path)?.write_all(contents.as_bytes())
+    }
+
+    #[allow(clippy::result_large_err)]
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Self> {
-        let contents = include_str!("../mupen64plus-input-gca.toml");
-        let cfg = toml::from_str(contents).unwrap();
+        let cfg = Self::default();
 
         let path = path.as_ref();
         let mut file = match File::create(path) {
@@ -55,14 +507,103 @@ impl Config {
             Err(_) => return Err(cfg),
         };
 
-        match file.write_all(contents.as_bytes()) {
+        match file.write_all(DEFAULT_CONFIG.as_bytes()) {
             Ok(_) => Ok(cfg),
             Err(_) => Err(cfg),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GcButton {
+    A,
+    B,
+    X,
+    Y,
+    Start,
+    Z,
+    L,
+    R,
+    DPadLeft,
+    DPadRight,
+    DPadDown,
+    DPadUp,
+}
+
+impl GcButton {
+    pub const ALL: [GcButton; 12] = [
+        GcButton::A,
+        GcButton::B,
+        GcButton::X,
+        GcButton::Y,
+        GcButton::Start,
+        GcButton::Z,
+        GcButton::L,
+        GcButton::R,
+        GcButton::DPadLeft,
+        GcButton::DPadRight,
+        GcButton::DPadDown,
+        GcButton::DPadUp,
+    ];
+
+    /// Check if the button is pressed. For L and R this is the digital click at the bottom of the
+    /// trigger.
+    pub fn is_pressed(&self, state: &ControllerState) -> bool {
+        match self {
+            GcButton::A => state.a,
+            GcButton::B => state.b,
+            GcButton::X => state.x,
+            GcButton::Y => state.y,
+            GcButton::Start => state.start,
+            GcButton::Z => state.z,
+            GcButton::L => state.l,
+            GcButton::R => state.r,
+            GcButton::DPadLeft => state.left,
+            GcButton::DPadRight => state.right,
+            GcButton::DPadDown => state.down,
+            GcButton::DPadUp => state.up,
+        }
+    }
+
+    /// Press or release the button. For L and R the analog trigger is fully pressed or released
+    /// as well.
+    pub fn set_pressed(&self, state: &mut ControllerState, pressed: bool) {
+        let trigger = if pressed { u8::MAX } else { 0 };
+        match self {
+            GcButton::A => state.a = pressed,
+            GcButton::B => state.b = pressed,
+            GcButton::X => state.x = pressed,
+            GcButton::Y => state.y = pressed,
+            GcButton::Start => state.start = pressed,
+            GcButton::Z => state.z = pressed,
+            GcButton::L => {
+                state.l = pressed;
+                state.trigger_left = trigger;
+            }
+            GcButton::R => {
+                state.r = pressed;
+                state.trigger_right = trigger;
+            }
+            GcButton::DPadLeft => state.left = pressed,
+            GcButton::DPadRight => state.right = pressed,
+            GcButton::DPadDown => state.down = pressed,
+            GcButton::DPadUp => state.up = pressed,
+        }
+    }
+}
+
+/// A combination of GC buttons that have to be held at the same time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Chord(pub Vec<GcButton>);
+
+impl Chord {
+    pub fn is_held(&self, state: &ControllerState) -> bool {
+        !self.0.is_empty() && self.0.iter().all(|b| b.is_pressed(state))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum N64Button {
     A,
     B,
@@ -81,6 +622,23 @@ pub enum N64Button {
 }
 
 impl N64Button {
+    pub const ALL: [N64Button; 14] = [
+        N64Button::A,
+        N64Button::B,
+        N64Button::DPadLeft,
+        N64Button::DPadRight,
+        N64Button::DPadDown,
+        N64Button::DPadUp,
+        N64Button::Start,
+        N64Button::Z,
+        N64Button::R,
+        N64Button::L,
+        N64Button::CLeft,
+        N64Button::CRight,
+        N64Button::CDown,
+        N64Button::CUp,
+    ];
+
     pub fn bit_pattern(&self) -> u32 {
         match self {
             N64Button::A => 0x0080,
@@ -100,3 +658,181 @@ impl N64Button {
         }
     }
 }
+
+/// When the adapter is read.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PollingMode {
+    /// The adapter is read about every millisecond by a background thread.
+    #[default]
+    Background,
+    /// The adapter is read when the game reads the first controller in a frame, falling back to
+    /// the state read by the background thread if the adapter does not respond in time. The
+    /// background thread only reads the adapter while the game is not reading it, so short taps
+    /// between frames are lost, and the stick filters only see one state per frame.
+    Synchronous,
+}
+
+/// How the position of the sticks is sampled from the states read from the adapter since the last
+/// time the game read the controller.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StickSampling {
+    /// The latest position.
+    #[default]
+    Latest,
+    /// The average position.
+    Average,
+    /// The position furthest from the centre.
+    Extremum,
+}
+
+/// The shape the control stick output is limited to.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub enum GateProfile {
+    /// No gate emulation, the output is a circle with a radius given by the sensitivity.
+    #[default]
+    Circle,
+    /// A new OEM N64 controller.
+    Oem,
+    /// An OEM N64 controller with a worn stick, which does not quite reach the edges.
+    WornOem,
+    /// A Hori Mini Pad, which has a larger range than the OEM controller.
+    Hori,
+}
+
+impl GateProfile {
+    /// The octagonal gate for this profile, or `None` if the output should not be limited to a
+    /// gate.
+    pub fn octagon(&self) -> Option<Octagon> {
+        let (cardinal, diagonal) = match self {
+            GateProfile::Circle => return None,
+            GateProfile::Oem => (80.0, 70.0),
+            GateProfile::WornOem => (72.0, 60.0),
+            GateProfile::Hori => (85.0, 75.0),
+        };
+
+        Some(Octagon { cardinal, diagonal })
+    }
+}
+
+/// How the C-stick is converted to C-buttons.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub enum CStickMode {
+    /// Each axis is converted on its own.
+    #[default]
+    Axes,
+    /// Only one C-button is pressed at a time.
+    FourWay,
+    /// Diagonals press two C-buttons, with every direction having the same sector width.
+    EightWay,
+    /// Diagonals press two C-buttons, but with narrow diagonal sectors.
+    CardinalPreferred,
+}
+
+impl CStickMode {
+    /// The width (in degrees) of the diagonal sectors, or `None` if each axis is converted on its
+    /// own.
+    pub fn diagonal_width(&self, cardinal_preferred_width: f32) -> Option<f32> {
+        match self {
+            CStickMode::Axes => None,
+            CStickMode::FourWay => Some(0.0),
+            CStickMode::EightWay => Some(45.0),
+            CStickMode::CardinalPreferred => Some(cardinal_preferred_width.clamp(0.0, 90.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_channel_config() {
+        let mut cfg = Config::default();
+        let step = MacroStep {
+            buttons: vec![N64Button::A, N64Button::CUp],
+            x: -20,
+            y: 80,
+            frames: Some(3),
+            ms: None,
+        };
+        cfg.channel.two.control_stick_notches = Some([[1, 2]; 8]);
+        cfg.channel.two.macros.push(Macro {
+            trigger: Chord(vec![GcButton::Y]),
+            steps: vec![step.clone()],
+        });
+        cfg.channel.two.recorded_macro.push(step.clone());
+        cfg.chords.push(ChordMapping {
+            buttons: Chord(vec![GcButton::X, GcButton::Y]),
+            press: N64Button::CDown,
+        });
+        cfg.channel.two.snap_zones.push(SnapZone {
+            angle: 90.0,
+            width: 10.0,
+        });
+
+        let cfg: Config = toml::from_str(&toml::to_string(&cfg).unwrap()).unwrap();
+        assert_eq!(Some([[1, 2]; 8]), cfg.channel.two.control_stick_notches);
+        assert_eq!(vec![step.clone()], cfg.channel.two.macros[0].steps);
+        assert_eq!(vec![step], cfg.channel.two.recorded_macro);
+        assert_eq!(N64Button::CDown, cfg.chords[0].press);
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut cfg = Config::default();
+        let mut mapping = cfg.controller_mapping.clone();
+        mapping.a = N64Button::CDown;
+        cfg.layers.push(Layer {
+            modifier: GcButton::Z,
+            mapping,
+        });
+
+        let mut state = ControllerState {
+            a: true,
+            ..ControllerState::new()
+        };
+        let (mapping, modifier) = cfg.mapping(&state);
+        assert_eq!(None, modifier);
+        assert_eq!(N64Button::A.bit_pattern(), mapping.bit_pattern(&state));
+
+        state.z = true;
+        let (mapping, modifier) = cfg.mapping(&state);
+        assert_eq!(Some(GcButton::Z), modifier);
+        GcButton::Z.set_pressed(&mut state, false);
+        assert_eq!(N64Button::CDown.bit_pattern(), mapping.bit_pattern(&state));
+    }
+
+    #[test]
+    fn test_walk_modifier() {
+        let mut cfg = ChannelConfig {
+            walk_modifier: WalkModifier {
+                button: Some(GcButton::X),
+                trigger: Some(GcTrigger::Left),
+                scale: 0.4,
+            },
+            ..Default::default()
+        };
+        let factor = |state: &ControllerState, cfg: &ChannelConfig| {
+            (cfg.walk_modifier.factor(state, cfg) * 1000.0).round() / 1000.0
+        };
+
+        let mut state = ControllerState::new();
+        assert_eq!(1.0, factor(&state, &cfg));
+        state.x = true;
+        assert_eq!(0.4, factor(&state, &cfg));
+
+        // The trigger scales the stick by how far it is pressed
+        state.x = false;
+        state.trigger_left = 255;
+        assert_eq!(0.4, factor(&state, &cfg));
+        state.trigger_left = 51;
+        assert_eq!(0.88, factor(&state, &cfg));
+
+        // The smallest factor is used, and the trigger range is calibrated
+        state.x = true;
+        assert_eq!(0.4, factor(&state, &cfg));
+        cfg.trigger_left.max = 51;
+        state.x = false;
+        assert_eq!(0.4, factor(&state, &cfg));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{
+    adapter::{AdapterState, Channel, ControllerState},
+    config::Config,
+};
+
+#[derive(Debug, Copy, Clone)]
+struct Connection {
+    connected: bool,
+    /// How many reports in a row have disagreed with `connected`.
+    changed_for: u8,
+    /// The last state read while the controller was connected.
+    last_state: ControllerState,
+}
+
+impl Connection {
+    const fn new() -> Self {
+        Self {
+            connected: false,
+            changed_for: 0,
+            last_state: ControllerState::new(),
+        }
+    }
+}
+
+/// Debounces the connection status of each controller.
+///
+/// A controller is only considered connected or disconnected after it has been reported as such
+/// for `connection_debounce_frames` reports in a row. Until then, a controller that seems to have
+/// been disconnected keeps its last state, and one that seems to have been connected stays
+/// disconnected.
+#[derive(Debug)]
+pub struct ConnectionDebouncer {
+    channels: [Connection; 4],
+}
+
+impl ConnectionDebouncer {
+    pub const fn new() -> Self {
+        Self {
+            channels: [Connection::new(); 4],
+        }
+    }
+
+    pub fn update(&mut self, state: &mut AdapterState, cfg: &Config) {
+        for (i, c) in self.channels.iter_mut().enumerate() {
+            let s = state.controller_state_mut(Channel::try_from(i).unwrap());
+            if s.is_connected() == c.connected {
+                c.changed_for = 0;
+            } else {
+                c.changed_for = c.changed_for.saturating_add(1);
+                if c.changed_for >= cfg.connection_debounce_frames {
+                    c.connected = !c.connected;
+                    c.changed_for = 0;
+                }
+            }
+
+            match (c.connected, s.is_connected()) {
+                (true, true) => c.last_state = *s,
+                (true, false) => *s = c.last_state,
+                (false, true) => *s = ControllerState::new(),
+                (false, false) => (),
+            }
+        }
+    }
+}
+
+impl Default for ConnectionDebouncer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_debouncer() {
+        let cfg = Config {
+            connection_debounce_frames: 2,
+            ..Config::default()
+        };
+        let mut debouncer = ConnectionDebouncer::new();
+        let mut update = |status, a| {
+            let mut state = AdapterState::new();
+            state.controller_0 = ControllerState {
+                status,
+                a,
+                ..ControllerState::new()
+            };
+            debouncer.update(&mut state, &cfg);
+            state.controller_0
+        };
+
+        assert!(!update(0x10, true).is_connected());
+        assert!(update(0x10, true).a);
+
+        // A single dropped report keeps the last state
+        let s = update(0, false);
+        assert!(s.is_connected() && s.a);
+        assert!(!update(0x10, false).a);
+
+        update(0, false);
+        assert!(!update(0, false).is_connected());
+    }
+}
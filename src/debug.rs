@@ -1,5 +1,6 @@
 use std::{
-    ffi::{c_void, CString},
+    ffi::c_void,
+    fmt::{self, Write},
     os::raw::{c_char, c_int},
     sync::{
         atomic::{AtomicPtr, Ordering},
@@ -15,25 +16,70 @@ pub fn init(debug_callback: DebugCallback, context_ptr: *mut c_void) {
 
 pub static DEBUG_INFO: Mutex<Option<DebugInfo>> = Mutex::new(None);
 
+/// Print a message through the debug callback of the core. Errors and warnings, and the status
+/// messages that report what the user has asked for, like calibration steps and latency reports,
+/// are printed in every build; info and verbose messages only in debug builds.
 macro_rules! debug_print {
     ($level:expr, $s:expr) => {
         debug_print!($level, $s,)
     };
     ($level:expr, $s:expr, $($arg:expr),*) => {{
-        if cfg!(debug_assertions) || $level <= $crate::debug::M64Message::Warning {
-            $crate::debug::__print_debug_message($level, format!($s $(, $arg)*));
+        if cfg!(debug_assertions)
+            || $level <= $crate::debug::M64Message::Warning
+            || $level == $crate::debug::M64Message::Status
+        {
+            $crate::debug::__print_debug_message($level, format_args!($s $(, $arg)*));
         }
     }};
 }
 
+/// The longest message that is passed to the debug callback, in bytes. Longer messages are cut
+/// off.
+const MAX_MESSAGE_LEN: usize = 511;
+
+/// A nul-terminated message that is formatted on the stack, so that printing does not allocate.
+struct MessageBuffer {
+    buf: [u8; MAX_MESSAGE_LEN + 1],
+    len: usize,
+}
+
+impl MessageBuffer {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_MESSAGE_LEN + 1],
+            len: 0,
+        }
+    }
+
+    fn as_ptr(&self) -> *const c_char {
+        self.buf.as_ptr().cast()
+    }
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars().filter(|&c| c != '\0') {
+            let len = c.len_utf8();
+            if self.len + len > MAX_MESSAGE_LEN {
+                break;
+            }
+            c.encode_utf8(&mut self.buf[self.len..]);
+            self.len += len;
+        }
+
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
-pub(crate) fn __print_debug_message(level: M64Message, message: String) {
+pub(crate) fn __print_debug_message(level: M64Message, message: fmt::Arguments) {
     match *DEBUG_INFO.lock().unwrap() {
         Some(ref di) => {
-            let message = CString::new(message).unwrap();
+            let mut buf = MessageBuffer::new();
+            let _ = buf.write_fmt(message);
             let context = di.context_ptr.load(Ordering::Acquire);
 
-            (di.callback)(context, level as c_int, message.as_ptr());
+            (di.callback)(context, level as c_int, buf.as_ptr());
         }
         None => {
             println!("{level:?}: {message}");
@@ -0,0 +1,91 @@
+/// The longest input delay, in frames.
+pub const MAX_INPUT_DELAY: u8 = 32;
+
+const BUFFER_LEN: usize = MAX_INPUT_DELAY as usize + 1;
+
+/// The bits of `BUTTONS` that hold the buttons, leaving out the stick.
+const BUTTONS_MASK: u32 = 0xFFFF;
+
+/// Delays the `BUTTONS` of one channel by a number of frames, using a ring buffer of the past
+/// frames.
+///
+/// When the delay is changed, it moves towards the new delay one frame at a time, and only when
+/// the buttons are the same in the frames around the change. A frame is then repeated or skipped
+/// without the buttons changing, so that no presses are lost or doubled.
+#[derive(Debug)]
+pub struct InputDelay {
+    buffer: [u32; BUFFER_LEN],
+    head: usize,
+    delay: usize,
+}
+
+impl InputDelay {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; BUFFER_LEN],
+            head: 0,
+            delay: 0,
+        }
+    }
+
+    /// The frame `age` frames ago, where 0 is the newest frame.
+    fn frame(&self, age: usize) -> u32 {
+        self.buffer[(self.head + BUFFER_LEN - age) % BUFFER_LEN]
+    }
+
+    /// Add a new frame, and get the delayed frame.
+    pub fn update(&mut self, value: u32, target: u8) -> u32 {
+        self.head = (self.head + 1) % BUFFER_LEN;
+        self.buffer[self.head] = value;
+
+        let target = target.min(MAX_INPUT_DELAY) as usize;
+        let same =
+            |a: usize, b: usize| self.frame(a) & BUTTONS_MASK == self.frame(b) & BUTTONS_MASK;
+        if target > self.delay && same(self.delay, self.delay + 1) {
+            self.delay += 1;
+        } else if target < self.delay && same(self.delay, self.delay - 1) {
+            self.delay -= 1;
+        }
+
+        self.frame(self.delay)
+    }
+
+    /// The current delay, in frames.
+    pub fn delay(&self) -> u8 {
+        self.delay as u8
+    }
+}
+
+impl Default for InputDelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_delay() {
+        let mut delay = InputDelay::new();
+        for i in 0..3 {
+            delay.update(0, 2);
+            assert_eq!((i + 1).min(2), delay.delay());
+        }
+
+        assert_eq!(0, delay.update(1, 2));
+        assert_eq!(0, delay.update(2, 2));
+        assert_eq!(1, delay.update(3, 2));
+
+        // The delay is only lowered when no buttons are skipped
+        assert_eq!(2, delay.update(4, 0));
+        assert_eq!(3, delay.update(4, 0));
+        assert_eq!(2, delay.delay());
+        assert_eq!(4, delay.update(4, 0));
+        assert_eq!(1, delay.delay());
+        assert_eq!(4, delay.update(5, 0));
+        assert_eq!(5, delay.update(5, 0));
+        assert_eq!(0, delay.delay());
+    }
+}
@@ -3,6 +3,7 @@
 #[allow(non_snake_case)]
 #[allow(dead_code)]
 #[allow(clippy::all)]
+#[allow(unknown_lints, unnecessary_transmutes)]
 mod bindings;
 pub use bindings::*;
 
@@ -0,0 +1,201 @@
+use crate::{
+    adapter::{AdapterState, Channel, StickOrigin},
+    config::{Config, StickConfig},
+};
+use std::time::{Duration, Instant};
+
+/// The stick is considered to be held in a direction when it is further out than this.
+const HELD_RADIUS: f32 = 40.0;
+/// The stick is considered to have returned to the centre when it is closer than this.
+const RETURN_RADIUS: f32 = 20.0;
+/// A return to the centre is considered a release (which may cause snapback) when it takes less
+/// time than this.
+const FAST_RETURN_TIME: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Copy, Clone)]
+struct Snapback {
+    /// Direction the stick was held in before being released.
+    direction: (f32, f32),
+    until: Instant,
+}
+
+/// Filters for one analog stick, working on the position relative to the origin of the stick.
+#[derive(Debug, Copy, Clone, Default)]
+struct StickFilter {
+    held: Option<((f32, f32), Instant)>,
+    snapback: Option<Snapback>,
+    smoothed: Option<(f32, f32)>,
+}
+
+impl StickFilter {
+    const fn new() -> Self {
+        Self {
+            held: None,
+            snapback: None,
+            smoothed: None,
+        }
+    }
+
+    fn filter(
+        &mut self,
+        (x, y): (f32, f32),
+        cfg: &StickConfig,
+        now: Instant,
+        dt: f32,
+    ) -> (f32, f32) {
+        let (mut x, mut y) = (x, y);
+
+        if cfg.snapback_window_ms > 0 {
+            let radius = x.hypot(y);
+            if radius >= HELD_RADIUS {
+                self.held = Some(((x / radius, y / radius), now));
+            } else if radius < RETURN_RADIUS {
+                if let Some((direction, at)) = self.held.take() {
+                    if now.duration_since(at) <= FAST_RETURN_TIME {
+                        self.snapback = Some(Snapback {
+                            direction,
+                            until: now + Duration::from_millis(cfg.snapback_window_ms as u64),
+                        });
+                    }
+                }
+            }
+
+            // Ignore any movement to the opposite side of where the stick was held, right after
+            // the stick has been released
+            if let Some(s) = self.snapback {
+                if now >= s.until {
+                    self.snapback = None;
+                } else if x * s.direction.0 + y * s.direction.1 < 0.0 {
+                    (x, y) = (0.0, 0.0);
+                }
+            }
+        }
+
+        if cfg.smoothing_ms > 0 {
+            let (sx, sy) = self.smoothed.get_or_insert((x, y));
+            let alpha = 1.0 - (-dt * 1000.0 / cfg.smoothing_ms as f32).exp();
+            *sx += alpha * (x - *sx);
+            *sy += alpha * (y - *sy);
+            (x, y) = (*sx, *sy);
+        } else {
+            self.smoothed = None;
+        }
+
+        (x, y)
+    }
+}
+
+fn offset(origin: StickOrigin, x: u8, y: u8) -> (f32, f32) {
+    (x as f32 - origin.x as f32, y as f32 - origin.y as f32)
+}
+
+fn position(origin: StickOrigin, (x, y): (f32, f32)) -> (u8, u8) {
+    (
+        (origin.x as f32 + x).round().clamp(0.0, 255.0) as u8,
+        (origin.y as f32 + y).round().clamp(0.0, 255.0) as u8,
+    )
+}
+
+/// Snapback suppression and smoothing for the sticks of each controller.
+///
+/// This runs on every state read from the adapter, between reading the state and storing it for
+/// `GetKeys`, since the filters need the full rate of the adapter to work well.
+#[derive(Debug)]
+pub struct StickFilters {
+    channels: [[StickFilter; 2]; 4],
+    last_update: Option<Instant>,
+}
+
+impl StickFilters {
+    pub const fn new() -> Self {
+        Self {
+            channels: [[StickFilter::new(); 2]; 4],
+            last_update: None,
+        }
+    }
+
+    pub fn update(&mut self, state: &mut AdapterState, cfg: &Config, now: Instant) {
+        let dt = self
+            .last_update
+            .map_or(0.0, |t| now.duration_since(t).as_secs_f32().min(0.1));
+        self.last_update = Some(now);
+
+        for (i, [stick, substick]) in self.channels.iter_mut().enumerate() {
+            let channel = Channel::try_from(i).unwrap();
+            let channel_cfg = cfg.channel(channel);
+            let s = state.controller_state_mut(channel);
+            if !s.is_connected() {
+                *stick = StickFilter::default();
+                *substick = StickFilter::default();
+                continue;
+            }
+
+            let filtered = stick.filter(
+                offset(s.stick_origin, s.stick_x, s.stick_y),
+                &channel_cfg.control_stick,
+                now,
+                dt,
+            );
+            (s.stick_x, s.stick_y) = position(s.stick_origin, filtered);
+
+            let filtered = substick.filter(
+                offset(s.substick_origin, s.substick_x, s.substick_y),
+                &channel_cfg.c_stick,
+                now,
+                dt,
+            );
+            (s.substick_x, s.substick_y) = position(s.substick_origin, filtered);
+        }
+    }
+}
+
+impl Default for StickFilters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapback_suppression() {
+        let cfg = StickConfig {
+            snapback_window_ms: 30,
+            ..Default::default()
+        };
+        let mut filter = StickFilter::default();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!((80.0, 0.0), filter.filter((80.0, 0.0), &cfg, at(0), 0.001));
+        assert_eq!((30.0, 0.0), filter.filter((30.0, 0.0), &cfg, at(4), 0.001));
+        assert_eq!((5.0, 0.0), filter.filter((5.0, 0.0), &cfg, at(8), 0.001));
+        assert_eq!((0.0, 0.0), filter.filter((-25.0, 3.0), &cfg, at(12), 0.001));
+        assert_eq!((0.0, 0.0), filter.filter((-10.0, 0.0), &cfg, at(20), 0.001));
+        assert_eq!(
+            (-10.0, 0.0),
+            filter.filter((-10.0, 0.0), &cfg, at(40), 0.001)
+        );
+    }
+
+    #[test]
+    fn test_slow_return_is_not_snapback() {
+        let cfg = StickConfig {
+            snapback_window_ms: 30,
+            ..Default::default()
+        };
+        let mut filter = StickFilter::default();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        filter.filter((80.0, 0.0), &cfg, at(0), 0.001);
+        filter.filter((30.0, 0.0), &cfg, at(100), 0.001);
+        filter.filter((10.0, 0.0), &cfg, at(200), 0.001);
+        assert_eq!(
+            (-25.0, 0.0),
+            filter.filter((-25.0, 0.0), &cfg, at(210), 0.001)
+        );
+    }
+}
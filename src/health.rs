@@ -0,0 +1,146 @@
+use crate::debug::M64Message;
+use std::time::{Duration, Instant};
+
+/// How long the polling rate and jitter are measured over.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Counters and rolling statistics of the reads from the adapter, to tell how well it is keeping
+/// up. Stock adapters report at 125 Hz, overclocked adapters at up to 1000 Hz.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdapterHealth {
+    /// How many full reports have been read.
+    pub reads: u64,
+    /// How many reads returned less than a full report.
+    pub short_reads: u64,
+    /// How many reports did not start with the header of a controller report.
+    pub invalid_reports: u64,
+    /// How many reads timed out.
+    pub timeouts: u64,
+    /// How many reads failed with each kind of error, other than a timeout.
+    pub errors: Vec<(rusb::Error, u64)>,
+    /// The number of full reports per second, over the last complete window of one second.
+    pub poll_rate: f64,
+    /// The standard deviation of the time between full reports, over the same window.
+    pub jitter: Duration,
+    last_read: Option<Instant>,
+    window_start: Option<Instant>,
+    window_reads: u32,
+    window_sum: f64,
+    window_sum_squares: f64,
+}
+
+impl AdapterHealth {
+    pub const fn new() -> Self {
+        Self {
+            reads: 0,
+            short_reads: 0,
+            invalid_reports: 0,
+            timeouts: 0,
+            errors: Vec::new(),
+            poll_rate: 0.0,
+            jitter: Duration::ZERO,
+            last_read: None,
+            window_start: None,
+            window_reads: 0,
+            window_sum: 0.0,
+            window_sum_squares: 0.0,
+        }
+    }
+
+    /// Record a full and valid report.
+    pub fn record_read(&mut self, now: Instant) {
+        self.reads += 1;
+        if let Some(last_read) = self.last_read {
+            let interval = (now - last_read).as_secs_f64();
+            self.window_reads += 1;
+            self.window_sum += interval;
+            self.window_sum_squares += interval * interval;
+        }
+        self.last_read = Some(now);
+
+        let window_start = *self.window_start.get_or_insert(now);
+        let elapsed = now - window_start;
+        if elapsed >= WINDOW {
+            let n = self.window_reads.max(1) as f64;
+            let mean = self.window_sum / n;
+            let variance = (self.window_sum_squares / n - mean * mean).max(0.0);
+            self.poll_rate = self.window_reads as f64 / elapsed.as_secs_f64();
+            self.jitter = Duration::from_secs_f64(variance.sqrt());
+
+            self.window_start = Some(now);
+            self.window_reads = 0;
+            self.window_sum = 0.0;
+            self.window_sum_squares = 0.0;
+        }
+    }
+
+    pub fn record_short_read(&mut self) {
+        self.short_reads += 1;
+    }
+
+    pub fn record_invalid_report(&mut self) {
+        self.invalid_reports += 1;
+    }
+
+    pub fn record_error(&mut self, error: rusb::Error) {
+        if error == rusb::Error::Timeout {
+            self.timeouts += 1;
+            return;
+        }
+
+        match self.errors.iter_mut().find(|(e, _)| *e == error) {
+            Some((_, count)) => *count += 1,
+            None => self.errors.push((error, 1)),
+        }
+    }
+
+    pub fn report(&self) {
+        debug_print!(
+            M64Message::Verbose,
+            "Adapter: {:.0} Hz with {:.2} ms jitter, {} reads, {} short reads, {} invalid reports, {} timeouts, errors: {:?}",
+            self.poll_rate,
+            self.jitter.as_secs_f64() * 1000.0,
+            self.reads,
+            self.short_reads,
+            self.invalid_reports,
+            self.timeouts,
+            self.errors
+        );
+    }
+}
+
+impl Default for AdapterHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapter_health() {
+        let mut health = AdapterHealth::new();
+        let start = Instant::now();
+        let at = |us| start + Duration::from_micros(us);
+
+        // 1000 Hz, alternating between 0.5 and 1.5 ms apart
+        let mut time = 0;
+        for i in 0..=1000 {
+            health.record_read(at(time));
+            time += if i % 2 == 0 { 500 } else { 1500 };
+        }
+        health.record_short_read();
+        health.record_error(rusb::Error::Timeout);
+        health.record_error(rusb::Error::Pipe);
+        health.record_error(rusb::Error::Pipe);
+
+        assert_eq!(1001, health.reads);
+        assert_eq!(1, health.short_reads);
+        assert_eq!(1, health.timeouts);
+        assert_eq!(vec![(rusb::Error::Pipe, 2)], health.errors);
+        assert!((health.poll_rate - 1000.0).abs() < 1.0);
+        assert!((health.jitter.as_secs_f64() * 1e6 - 500.0).abs() < 1.0);
+    }
+}
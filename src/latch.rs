@@ -0,0 +1,204 @@
+use crate::{
+    adapter::{AdapterState, Channel, ControllerState},
+    config::{ChannelConfig, Config, ControllerMapping, GcButton, N64Button},
+};
+use std::time::{Duration, Instant};
+
+/// How long the controller rumbles when a button is latched.
+const RUMBLE_TIME: Duration = Duration::from_millis(80);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Latch {
+    Released,
+    Latched,
+    /// A sticky button that has been used with another button, and is released together with it.
+    Used,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Normal,
+    Toggle,
+    Sticky,
+}
+
+fn bits(buttons: &[N64Button]) -> u32 {
+    buttons.iter().fold(0, |bits, b| bits | b.bit_pattern())
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LatchChannel {
+    held: [bool; 12],
+    latches: [Latch; 12],
+    rumble_until: Option<Instant>,
+}
+
+impl LatchChannel {
+    const fn new() -> Self {
+        Self {
+            held: [false; 12],
+            latches: [Latch::Released; 12],
+            rumble_until: None,
+        }
+    }
+
+    fn update(
+        &mut self,
+        s: &mut ControllerState,
+        cfg: &ChannelConfig,
+        mapping: &ControllerMapping,
+        now: Instant,
+    ) {
+        let toggle = bits(&cfg.toggle_buttons);
+        let sticky = bits(&cfg.sticky_buttons);
+        let kinds = GcButton::ALL.map(|b| {
+            let bit = mapping.get(b).bit_pattern();
+            if bit & toggle != 0 {
+                Kind::Toggle
+            } else if bit & sticky != 0 {
+                Kind::Sticky
+            } else {
+                Kind::Normal
+            }
+        });
+        let held = GcButton::ALL.map(|b| b.is_pressed(s));
+
+        let mut latched = false;
+        for i in 0..GcButton::ALL.len() {
+            if !held[i] || self.held[i] {
+                continue;
+            }
+
+            match kinds[i] {
+                Kind::Normal | Kind::Toggle => {
+                    for (latch, kind) in self.latches.iter_mut().zip(kinds) {
+                        if kind == Kind::Sticky && *latch == Latch::Latched {
+                            *latch = Latch::Used;
+                        }
+                    }
+                }
+                Kind::Sticky => (),
+            }
+
+            if kinds[i] != Kind::Normal {
+                self.latches[i] = match self.latches[i] {
+                    Latch::Released => {
+                        latched = true;
+                        Latch::Latched
+                    }
+                    Latch::Latched | Latch::Used => Latch::Released,
+                };
+            }
+        }
+
+        // Used sticky buttons are released when none of the other buttons are held anymore
+        let other_held = held
+            .iter()
+            .zip(kinds)
+            .any(|(&h, kind)| h && kind != Kind::Sticky);
+        for latch in &mut self.latches {
+            if *latch == Latch::Used && !other_held {
+                *latch = Latch::Released;
+            }
+        }
+
+        self.held = held;
+        for (i, button) in GcButton::ALL.iter().enumerate() {
+            let latched = self.latches[i] != Latch::Released;
+            match kinds[i] {
+                Kind::Normal => (),
+                Kind::Toggle => button.set_pressed(s, latched),
+                Kind::Sticky => button.set_pressed(s, latched || held[i]),
+            }
+        }
+
+        if latched {
+            self.rumble_until = Some(now + RUMBLE_TIME);
+        }
+    }
+}
+
+/// Toggle and sticky buttons for each controller.
+///
+/// A toggle button is latched on when pressed, and released when pressed again. A sticky button
+/// is latched on when pressed, and released after the next other button has been pressed and
+/// released (or when the sticky button is pressed again). The controller rumbles shortly when a
+/// button is latched.
+#[derive(Debug)]
+pub struct Latches {
+    channels: [LatchChannel; 4],
+}
+
+impl Latches {
+    pub const fn new() -> Self {
+        Self {
+            channels: [LatchChannel::new(); 4],
+        }
+    }
+
+    pub fn update(&mut self, state: &mut AdapterState, cfg: &Config, now: Instant) {
+        for (i, latches) in self.channels.iter_mut().enumerate() {
+            let channel = Channel::try_from(i).unwrap();
+            let s = state.controller_state_mut(channel);
+            if !s.is_connected() {
+                *latches = LatchChannel::new();
+                continue;
+            }
+
+            let (mapping, _) = cfg.mapping(s);
+            latches.update(s, cfg.channel(channel), mapping, now);
+        }
+    }
+
+    /// Get which controllers should rumble to give feedback for latched buttons.
+    pub fn rumble(&self, now: Instant) -> [bool; 4] {
+        self.channels
+            .map(|c| c.rumble_until.is_some_and(|until| now < until))
+    }
+}
+
+impl Default for Latches {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latches() {
+        let cfg = Config::default();
+        let channel_cfg = ChannelConfig {
+            toggle_buttons: vec![N64Button::R],
+            sticky_buttons: vec![N64Button::Z],
+            ..Default::default()
+        };
+        let mut latches = LatchChannel::new();
+        let now = Instant::now();
+        let mut update = |a, l, r| {
+            let mut s = ControllerState {
+                a,
+                l,
+                r,
+                ..ControllerState::new()
+            };
+            latches.update(&mut s, &channel_cfg, &cfg.controller_mapping, now);
+            (s.a, s.l, s.r)
+        };
+
+        // R toggles
+        assert_eq!((false, false, true), update(false, false, true));
+        assert_eq!((false, false, true), update(false, false, false));
+        assert_eq!((false, false, false), update(false, false, true));
+        assert_eq!((false, false, false), update(false, false, false));
+
+        // L (mapped to Z) sticks until A has been pressed and released
+        assert_eq!((false, true, false), update(false, true, false));
+        assert_eq!((false, true, false), update(false, false, false));
+        assert_eq!((true, true, false), update(true, false, false));
+        assert_eq!((true, true, false), update(true, false, false));
+        assert_eq!((false, false, false), update(false, false, false));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{debug::M64Message, sampling::Sample};
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+const BUCKET_WIDTH: Duration = Duration::from_micros(100);
+/// The histograms go up to 100 ms, longer times are counted in the last bucket.
+const BUCKETS: usize = 1000;
+/// A press that has not reached the core after this long is assumed to have been lost, e.g. to a
+/// macro, and is not measured.
+const MAX_PRESS_LATENCY: Duration = Duration::from_secs(1);
+/// The bits of `BUTTONS` that hold the buttons, leaving out the stick.
+const BUTTONS_MASK: u32 = 0xFFFF;
+
+/// The distribution of a latency.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// A histogram of latencies, with buckets of 0.1 ms. It is recorded and read through atomics, so
+/// that `GetKeys` records it without waiting for a reader.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [AtomicU32; BUCKETS],
+    count: AtomicU64,
+    min_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU32::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            min_nanos: AtomicU64::new(u64::MAX),
+            max_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let bucket = (latency.as_nanos() / BUCKET_WIDTH.as_nanos()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.min_nanos.fetch_min(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.min_nanos.store(u64::MAX, Ordering::Relaxed);
+        self.max_nanos.store(0, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Get the distribution of the recorded latencies. The percentiles are rounded up to the bucket
+    /// width. While latencies are being recorded, the summary may be off by the latencies recorded
+    /// while it was taken.
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let buckets: [u32; BUCKETS] =
+            std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed));
+        let count = buckets.iter().map(|&n| n as u64).sum::<u64>();
+        if count == 0 {
+            return None;
+        }
+
+        let min = Duration::from_nanos(self.min_nanos.load(Ordering::Relaxed));
+        let max = Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)).max(min);
+        let percentile = |fraction: f64| {
+            let rank = ((count as f64 * fraction).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (i, &n) in buckets.iter().enumerate() {
+                seen += n as u64;
+                if seen >= rank {
+                    return (BUCKET_WIDTH * (i as u32 + 1)).clamp(min, max);
+                }
+            }
+
+            max
+        };
+
+        Some(LatencySummary {
+            count,
+            min,
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+            max,
+        })
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The latencies of one channel.
+#[derive(Debug, Default)]
+pub struct ChannelLatency {
+    /// How old the state read from the adapter is when `GetKeys` uses it.
+    pub age: Histogram,
+    /// How long it takes from a button press arriving from the adapter until the N64 button it is
+    /// mapped to is written by `GetKeys`, including any input delay.
+    pub press: Histogram,
+}
+
+impl ChannelLatency {
+    pub const fn new() -> Self {
+        Self {
+            age: Histogram::new(),
+            press: Histogram::new(),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.age.reset();
+        self.press.reset();
+    }
+
+    pub fn report(&self, channel: usize) {
+        let format = |summary: Option<LatencySummary>| match summary {
+            Some(s) => format!(
+                "min {:.1} ms, p50 {:.1} ms, p99 {:.1} ms, max {:.1} ms ({} samples)",
+                s.min.as_secs_f64() * 1000.0,
+                s.p50.as_secs_f64() * 1000.0,
+                s.p99.as_secs_f64() * 1000.0,
+                s.max.as_secs_f64() * 1000.0,
+                s.count
+            ),
+            None => "no samples".to_owned(),
+        };
+
+        if self.age.count() == 0 {
+            return;
+        }
+
+        debug_print!(
+            M64Message::Status,
+            "Latency for controller {}: input age {}; presses {}",
+            channel + 1,
+            format(self.age.summary()),
+            format(self.press.summary())
+        );
+    }
+}
+
+/// Follows the presses of one channel through `GetKeys`, to record their latency.
+#[derive(Debug, Default)]
+pub struct LatencyRecorder {
+    last_buttons: u32,
+    /// The press that is waiting to reach the game with the N64 buttons it presses, and the last
+    /// press that was seen, since the frame snapshot gives the same sample to every read in a frame.
+    pending_press: Option<(Instant, u32)>,
+    seen_press: Option<Instant>,
+}
+
+impl LatencyRecorder {
+    pub const fn new() -> Self {
+        Self {
+            last_buttons: 0,
+            pending_press: None,
+            seen_press: None,
+        }
+    }
+
+    /// Record the latencies of a call to `GetKeys`, which used `sample` and wrote `buttons`.
+    /// `pressed` are the N64 buttons that the presses in the sample are mapped to, so that a press
+    /// is only measured when one of those buttons is written, and not when a button is pressed by
+    /// something else, like turbo or a macro.
+    pub fn record(
+        &mut self,
+        latency: &ChannelLatency,
+        sample: &Sample,
+        pressed: u32,
+        buttons: u32,
+        now: Instant,
+    ) {
+        if let Some(read_at) = sample.read_at {
+            latency.age.record(now - read_at);
+        }
+
+        let pressed = pressed & BUTTONS_MASK;
+        if let Some(pressed_at) = sample.pressed_at {
+            if Some(pressed_at) != self.seen_press && pressed != 0 {
+                self.seen_press = Some(pressed_at);
+                self.pending_press = Some(match self.pending_press {
+                    Some((at, bits)) => (at, bits | pressed),
+                    None => (pressed_at, pressed),
+                });
+            }
+        }
+
+        let buttons = buttons & BUTTONS_MASK;
+        let rising = buttons & !self.last_buttons;
+        self.last_buttons = buttons;
+
+        if let Some((pressed_at, bits)) = self.pending_press {
+            let elapsed = now - pressed_at;
+            if rising & bits != 0 {
+                latency.press.record(elapsed);
+                self.pending_press = None;
+            } else if elapsed > MAX_PRESS_LATENCY {
+                self.pending_press = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::ControllerState;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new();
+        assert_eq!(None, histogram.summary());
+
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms) - Duration::from_micros(50));
+        }
+        let summary = histogram.summary().unwrap();
+        assert_eq!(100, summary.count);
+        assert_eq!(Duration::from_micros(950), summary.min);
+        assert_eq!(Duration::from_millis(50), summary.p50);
+        assert_eq!(Duration::from_millis(99), summary.p99);
+        assert_eq!(Duration::from_micros(99_950), summary.max);
+
+        histogram.reset();
+        assert_eq!(None, histogram.summary());
+    }
+
+    #[test]
+    fn test_press_latency() {
+        let latency = ChannelLatency::new();
+        let mut recorder = LatencyRecorder::new();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let sample = |read_at, pressed_at| Sample {
+            state: ControllerState::new(),
+            read_at: Some(read_at),
+            pressed_at,
+            pressed: 0,
+            reset_held: false,
+        };
+        let (a, b) = (0x80, 0x40);
+
+        recorder.record(&latency, &sample(at(0), None), 0, 0, at(1));
+        recorder.record(&latency, &sample(at(10), Some(at(5))), a, 0, at(16));
+        // B is pressed by something else, like turbo
+        recorder.record(&latency, &sample(at(20), None), 0, b, at(23));
+        recorder.record(&latency, &sample(at(30), None), 0, a | b, at(33));
+        recorder.record(&latency, &sample(at(40), None), 0, a | b, at(50));
+
+        let press = latency.press.summary().unwrap();
+        assert_eq!(1, press.count);
+        assert_eq!(Duration::from_millis(28), press.max);
+
+        // A press of a button that is not mapped to anything is not measured
+        recorder.record(&latency, &sample(at(60), Some(at(55))), 0, 0, at(66));
+        recorder.record(&latency, &sample(at(70), None), 0, a, at(76));
+        assert_eq!(1, latency.press.summary().unwrap().count);
+        assert_eq!(
+            Duration::from_millis(10),
+            latency.age.summary().unwrap().max
+        );
+    }
+}
@@ -1,30 +1,65 @@
 #[macro_use]
 mod debug;
 pub mod adapter;
+pub mod binding;
+pub mod buffer;
+pub mod calibration;
 pub mod config;
+pub mod connection;
+pub mod delay;
 mod ffi;
+pub mod filter;
+pub mod health;
+pub mod latch;
+pub mod latency;
+pub mod macros;
+pub mod mapping;
+pub mod origin;
+pub mod sampling;
 #[macro_use]
 mod static_cstr;
+pub mod stats;
+pub mod stick;
+pub mod trigger;
+pub mod turbo;
 
 use adapter::{AdapterState, Channel};
-use config::Config;
+use binding::TapBindings;
+use buffer::TripleBuffer;
+use calibration::Calibrator;
+use config::{ChannelConfig, Config, N64Button, PollingMode};
+use connection::ConnectionDebouncer;
 use debug::M64Message;
+use delay::{InputDelay, MAX_INPUT_DELAY};
 use ffi::*;
+use filter::StickFilters;
+use health::AdapterHealth;
+use latch::Latches;
+use latency::{ChannelLatency, LatencyRecorder};
+use macros::{MacroPlayer, Recording};
+use mapping::MappingTable;
 use once_cell::sync::OnceCell;
+use origin::OriginTracker;
+use sampling::{FrameSnapshot, Sample, SampleChannel, Samples, FRAME_GAP};
 use static_cstr::StaticCStr;
+use stats::{AtomicPollStats, PollStats};
 use std::{
+    cell::UnsafeCell,
     ffi::{c_void, CStr},
     mem::ManuallyDrop,
     os::raw::{c_char, c_int, c_uchar},
-    path::Path,
+    path::{Path, PathBuf},
     ptr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU8, Ordering},
         Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use stick::{Notches, Octagon, StickButtons};
+use trigger::TriggerButtons;
+use turbo::Turbo;
 
 #[cfg(unix)]
 use libloading::os::unix::Library;
@@ -48,10 +83,103 @@ static PLUGIN_INFO: PluginInfo = PluginInfo {
 static IS_INIT: AtomicBool = AtomicBool::new(false);
 
 static CONFIG: OnceCell<Config> = OnceCell::new();
+static CONFIG_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+static MAPPING_TABLE: OnceCell<MappingTable> = OnceCell::new();
 
 static ADAPTER: OnceCell<GcAdapter> = OnceCell::new();
-static ADAPTER_STATE: Mutex<AdapterState> = Mutex::new(AdapterState::new());
-static ADAPTER_RUMBLE: Mutex<[u8; 4]> = Mutex::new([0; 4]);
+static SAMPLES: SampleChannel = SampleChannel::new();
+static POLL_STATS: AtomicPollStats = AtomicPollStats::new();
+/// Recorded by `GetKeys`, and read by the Rust API and the latency reports.
+static INPUT_LATENCY: [ChannelLatency; 4] = [const { ChannelLatency::new() }; 4];
+/// Set by the hotkey, and the latency is reported by the adapter thread, so that `GetKeys` does
+/// not format the report.
+static LATENCY_REPORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// The input delay of each channel set with `set_input_delay`, which overrides the config, or
+/// `NO_INPUT_DELAY_OVERRIDE`.
+static INPUT_DELAY_OVERRIDE: [AtomicU8; 4] = [const { AtomicU8::new(NO_INPUT_DELAY_OVERRIDE) }; 4];
+const NO_INPUT_DELAY_OVERRIDE: u8 = u8::MAX;
+/// The current input delay of each channel, stored by `GetKeys`.
+static INPUT_DELAY: [AtomicU8; 4] = [const { AtomicU8::new(0) }; 4];
+/// The last macro recorded in `GetKeys`, which the adapter thread saves to the config file.
+static RECORDED_MACRO: TripleBuffer<(Channel, Recording)> =
+    TripleBuffer::new((Channel::One, Recording::new()));
+/// Set by `GetKeys` when it has published a recorded macro that has not been saved yet.
+static MACRO_RECORDED: AtomicBool = AtomicBool::new(false);
+/// Set by `GetKeys` on each call in synchronous mode, so that the adapter thread knows when the
+/// game has stopped reading the controllers.
+static SYNC_POLLED: AtomicBool = AtomicBool::new(false);
+/// Rumble requested by the game, and short pulses used as feedback by the plugin.
+#[derive(Debug, Copy, Clone)]
+struct Rumble {
+    game: [u8; 4],
+    pulse: [bool; 4],
+}
+
+static ADAPTER_RUMBLE: Mutex<Rumble> = Mutex::new(Rumble {
+    game: [0; 4],
+    pulse: [false; 4],
+});
+/// Set when the rumble has changed, and is written to the adapter by the adapter thread.
+static RUMBLE_CHANGED: AtomicBool = AtomicBool::new(false);
+
+static STICK_NOTCHES: Mutex<[Option<Notches>; 4]> = Mutex::new([None; 4]);
+/// Set for a channel when it has been calibrated, and the notches are saved to the config file by
+/// the adapter thread.
+static NOTCHES_CALIBRATED: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+
+/// State that is kept between calls to `GetKeys` for a channel.
+#[derive(Debug)]
+struct ChannelState {
+    c_buttons: StickButtons,
+    control_stick_d_pad: StickButtons,
+    c_stick_d_pad: StickButtons,
+    trigger_left: TriggerButtons,
+    trigger_right: TriggerButtons,
+    macros: MacroPlayer,
+    input_delay: InputDelay,
+    latency: LatencyRecorder,
+}
+
+impl ChannelState {
+    const fn new() -> Self {
+        Self {
+            c_buttons: StickButtons::new(),
+            control_stick_d_pad: StickButtons::new(),
+            c_stick_d_pad: StickButtons::new(),
+            trigger_left: TriggerButtons::new(),
+            trigger_right: TriggerButtons::new(),
+            macros: MacroPlayer::new(),
+            input_delay: InputDelay::new(),
+            latency: LatencyRecorder::new(),
+        }
+    }
+}
+
+/// The state of `GetKeys`.
+#[derive(Debug)]
+struct Reader {
+    snapshot: FrameSnapshot,
+    channels: [ChannelState; 4],
+}
+
+/// Holds the state of `GetKeys` without a lock, since only `GetKeys` accesses it, and the core
+/// calls `GetKeys` from the emulator thread only.
+struct ReaderCell(UnsafeCell<Reader>);
+
+// Safety: the reader is only accessed by `GetKeys`, which the core does not call from several
+// threads at once
+unsafe impl Sync for ReaderCell {}
+
+static READER: ReaderCell = ReaderCell(UnsafeCell::new(Reader {
+    snapshot: FrameSnapshot::new(),
+    channels: [
+        ChannelState::new(),
+        ChannelState::new(),
+        ChannelState::new(),
+        ChannelState::new(),
+    ],
+}));
 
 fn data_crc(data: &[u8], len: usize) -> u8 {
     let mut remainder = data[0];
@@ -142,8 +270,6 @@ pub unsafe extern "C" fn PluginStartup(
         return m64p_error_M64ERR_INCOMPATIBLE;
     }
 
-    start_read_thread();
-
     let cfg_file_name = "mupen64plus-input-gca.toml";
     let cfg_path = if let Ok(sym) =
         lib.get::<extern "C" fn() -> *const c_char>(b"ConfigGetUserConfigPath\0")
@@ -172,6 +298,30 @@ pub unsafe extern "C" fn PluginStartup(
         debug_print!(M64Message::Error, "Config error: {:?}", e);
         Config::create(&cfg_path).unwrap_or_else(|e| e)
     }));
+    let _ = CONFIG_PATH.set(cfg_path);
+
+    let cfg = CONFIG.get().unwrap();
+    let _ = MAPPING_TABLE.set(MappingTable::new(cfg));
+    let mut notches = STICK_NOTCHES.lock().unwrap();
+    for (i, n) in notches.iter_mut().enumerate() {
+        *n = cfg
+            .channel(Channel::try_from(i).unwrap())
+            .control_stick_notches
+            .filter(|n| {
+                let valid = stick::notches_valid(n);
+                if !valid {
+                    debug_print!(
+                        M64Message::Warning,
+                        "Ignoring invalid control stick notches for controller {}, please recalibrate",
+                        i + 1
+                    );
+                }
+                valid
+            });
+    }
+    drop(notches);
+
+    start_read_thread();
 
     m64p_error_M64ERR_SUCCESS
 }
@@ -242,7 +392,10 @@ pub unsafe extern "C" fn InitiateControllers(control_info: CONTROL_INFO) {
         (*controls.add(i)).Plugin = PLUGIN_RAW as i32;
     }
 
-    if !ADAPTER_STATE.lock().unwrap().any_connected() {
+    // Safety: the core does not call `GetKeys` until the controllers are initiated, so the samples
+    // are not read by two threads at once
+    let frame = unsafe { SAMPLES.read() };
+    if !frame.any_connected() {
         debug_print!(
             M64Message::Warning,
             "No controllers connected, but hotplugging is supported"
@@ -250,6 +403,18 @@ pub unsafe extern "C" fn InitiateControllers(control_info: CONTROL_INFO) {
     }
 }
 
+/// Like an N64 controller, report the reset bit instead of Start while the origin is reset. This
+/// goes by the latest state, since the buttons of the sample are combined over the frame.
+fn report_reset(keys: &mut BUTTONS, sample: &Sample) {
+    if sample.reset_held {
+        // Safety: both fields of the union are the same 32 bits
+        unsafe {
+            keys.__bindgen_anon_1.set_START_BUTTON(0);
+            keys.__bindgen_anon_1.set_Reserved2(1);
+        }
+    }
+}
+
 /// Get the state of the buttons by reading from the adapter.
 ///
 /// # Safety
@@ -257,10 +422,24 @@ pub unsafe extern "C" fn InitiateControllers(control_info: CONTROL_INFO) {
 /// `keys` must point to an intialized `BUTTONS` union.
 #[no_mangle]
 pub unsafe extern "C" fn GetKeys(control: c_int, keys: *mut BUTTONS) {
-    let s = ADAPTER_STATE
-        .lock()
-        .unwrap()
-        .controller_state(Channel::try_from(control).unwrap());
+    let channel = Channel::try_from(control).unwrap();
+    let cfg = CONFIG.get().unwrap();
+    if cfg.polling_mode == PollingMode::Synchronous {
+        poll_synchronously(cfg);
+    }
+
+    // Safety: the reader is only accessed here, and `GetKeys` is only called from the emulator thread
+    let reader = &mut *READER.0.get();
+
+    let now = Instant::now();
+    // Safety: the samples are only read by `GetKeys`
+    let frame = unsafe { SAMPLES.read() };
+    let sample = if cfg.frame_snapshot {
+        reader.snapshot.read(channel, &frame, &SAMPLES, now)
+    } else {
+        SAMPLES.take(channel, &frame)
+    };
+    let s = sample.state;
     if !s.is_connected() {
         return;
     }
@@ -268,62 +447,152 @@ pub unsafe extern "C" fn GetKeys(control: c_int, keys: *mut BUTTONS) {
     let keys = &mut *keys;
     keys.Value = 0;
 
-    let cfg = CONFIG.get().unwrap();
+    let channel_cfg = cfg.channel(channel);
+    let stick_deadzone = s.stick_deadzone.unwrap_or(cfg.control_stick_deadzone);
+    let substick_deadzone = s.substick_deadzone.unwrap_or(cfg.c_stick_deadzone);
+
+    let gate = cfg.control_stick_gate.octagon();
+    let notches = frame.notches[channel as usize];
+    let (stick_x, stick_y) = if let Some(notches) = notches {
+        let (x, y) = s.stick();
+        let gate = gate.unwrap_or(Octagon::CIRCLE);
+        stick::remap_notches(x, y, stick_deadzone, &notches, &gate)
+    } else {
+        let (x, y) = s.stick_with_deadzone(stick_deadzone, cfg.control_stick_sensitivity);
+        match gate {
+            Some(octagon) => octagon.apply(x, y),
+            None => (x, y),
+        }
+    };
+    let (stick_x, stick_y) = channel_cfg
+        .d_pad_to_stick
+        .and_then(|magnitude| stick::d_pad_position(&s, magnitude))
+        .unwrap_or((stick_x, stick_y));
+    let (stick_x, stick_y) = stick::snap(stick_x, stick_y, &channel_cfg.snap_zones);
+
+    let walk = channel_cfg.walk_modifier.factor(&s, channel_cfg);
+    let stick_x = (stick_x as f32 * walk).round() as i8;
+    let stick_y = (stick_y as f32 * walk).round() as i8;
+
     let (stick_x, stick_y) =
-        s.stick_with_deadzone(cfg.control_stick_deadzone, cfg.control_stick_sensitivity);
-    let (substick_x, substick_y) = s.substick_with_deadzone(cfg.c_stick_deadzone);
+        stick::fixed_position(&s, stick_x, stick_y, &channel_cfg.fixed_positions)
+            .unwrap_or((stick_x, stick_y));
 
-    if s.right {
-        keys.Value |= cfg.controller_mapping.d_pad_right.bit_pattern();
-    }
-    if s.left {
-        keys.Value |= cfg.controller_mapping.d_pad_left.bit_pattern();
-    }
-    if s.down {
-        keys.Value |= cfg.controller_mapping.d_pad_down.bit_pattern();
-    }
-    if s.up {
-        keys.Value |= cfg.controller_mapping.d_pad_up.bit_pattern();
-    }
-    if s.start {
-        keys.Value |= cfg.controller_mapping.start.bit_pattern();
-    }
-    if s.a {
-        keys.Value |= cfg.controller_mapping.a.bit_pattern();
-    }
-    if s.b {
-        keys.Value |= cfg.controller_mapping.b.bit_pattern();
-    }
-    if s.x {
-        keys.Value |= cfg.controller_mapping.x.bit_pattern();
-    }
-    if s.y {
-        keys.Value |= cfg.controller_mapping.y.bit_pattern();
-    }
-    if substick_x < 0 {
-        keys.Value |= cfg.controller_mapping.c_stick_left.bit_pattern();
-    }
-    if substick_x > 0 {
-        keys.Value |= cfg.controller_mapping.c_stick_right.bit_pattern();
-    }
-    if substick_y < 0 {
-        keys.Value |= cfg.controller_mapping.c_stick_down.bit_pattern();
-    }
-    if substick_y > 0 {
-        keys.Value |= cfg.controller_mapping.c_stick_up.bit_pattern();
-    }
-    if s.l || s.trigger_left > cfg.trigger_threshold {
-        keys.Value |= cfg.controller_mapping.l.bit_pattern();
+    let channel_state = &mut reader.channels[channel as usize];
+
+    let (substick_x, substick_y) = s.substick();
+    let substick_release = cfg
+        .c_stick_release_deadzone
+        .unwrap_or(substick_deadzone)
+        .min(substick_deadzone);
+
+    let table = MAPPING_TABLE.get().unwrap();
+    let mapped = table.map(channel, &s);
+    let mapping = mapped.mapping;
+    keys.Value |= mapped.bits;
+    if mapped.c_stick {
+        let c_buttons = match cfg.c_stick_mode.diagonal_width(cfg.c_stick_diagonal_width) {
+            Some(width) => channel_state.c_buttons.update_sectors(
+                substick_x,
+                substick_y,
+                substick_deadzone,
+                substick_release,
+                width,
+            ),
+            None => channel_state.c_buttons.update_axes(
+                substick_x,
+                substick_y,
+                substick_deadzone,
+                substick_release,
+            ),
+        };
+        keys.Value |= c_buttons.bit_pattern(
+            mapping.c_stick_right,
+            mapping.c_stick_up,
+            mapping.c_stick_left,
+            mapping.c_stick_down,
+        );
     }
-    if s.r || s.trigger_right > cfg.trigger_threshold {
-        keys.Value |= cfg.controller_mapping.r.bit_pattern();
+
+    let d_pad = [
+        (
+            channel_cfg.control_stick_to_d_pad,
+            &mut channel_state.control_stick_d_pad,
+            s.stick(),
+        ),
+        (
+            channel_cfg.c_stick_to_d_pad,
+            &mut channel_state.c_stick_d_pad,
+            s.substick(),
+        ),
+    ];
+    for (threshold, buttons, (x, y)) in d_pad {
+        if let Some(threshold) = threshold {
+            keys.Value |= buttons
+                .update_sectors(x, y, threshold, threshold, 45.0)
+                .bit_pattern(
+                    N64Button::DPadRight,
+                    N64Button::DPadUp,
+                    N64Button::DPadLeft,
+                    N64Button::DPadDown,
+                );
+        }
     }
-    if s.z {
-        keys.Value |= cfg.controller_mapping.z.bit_pattern();
+    keys.Value |= channel_state.trigger_left.update(
+        mapped.left.0,
+        mapped.left.1,
+        &channel_cfg.trigger_left,
+        cfg.trigger_threshold,
+        mapping.l,
+    );
+    keys.Value |= channel_state.trigger_right.update(
+        mapped.right.0,
+        mapped.right.1,
+        &channel_cfg.trigger_right,
+        cfg.trigger_threshold,
+        mapping.r,
+    );
+    keys.Value |= s.bound_buttons;
+
+    report_reset(keys, &sample);
+
+    let ((buttons, stick_x, stick_y), recorded) = channel_state.macros.update(
+        &s,
+        channel_cfg,
+        &cfg.hotkeys,
+        (keys.Value, stick_x, stick_y),
+        Instant::now(),
+    );
+    if let Some(recording) = recorded {
+        // Safety: recorded macros are only published by `GetKeys`
+        unsafe { RECORDED_MACRO.publish((channel, *recording)) };
+        MACRO_RECORDED.store(true, Ordering::Release);
     }
 
+    keys.Value = buttons;
     keys.__bindgen_anon_1.set_X_AXIS(stick_x as i32);
     keys.__bindgen_anon_1.set_Y_AXIS(stick_y as i32);
+
+    let delay = match INPUT_DELAY_OVERRIDE[channel as usize].load(Ordering::Relaxed) {
+        NO_INPUT_DELAY_OVERRIDE => channel_cfg.input_delay_frames,
+        frames => frames,
+    };
+    keys.Value = channel_state.input_delay.update(keys.Value, delay);
+    INPUT_DELAY[channel as usize].store(channel_state.input_delay.delay(), Ordering::Relaxed);
+
+    // Presses that are replaced by a macro do not reach the game
+    let pressed = if sample.pressed != 0 && !channel_state.macros.is_playing() {
+        table.pressed_bits(channel, &s, sample.pressed)
+    } else {
+        0
+    };
+    channel_state.latency.record(
+        &INPUT_LATENCY[channel as usize],
+        &sample,
+        pressed,
+        keys.Value,
+        Instant::now(),
+    );
 }
 
 /// Process the command and possibly read the controller. Currently unused, since raw data is disabled.
@@ -370,16 +639,8 @@ pub unsafe extern "C" fn ControllerCommand(control: c_int, command: *mut c_uchar
             let dw_address = ((*command.add(3) as u32) << 8) + ((*command.add(4) & 0xE0) as u32);
             if dw_address == 0xC000 {
                 let rumble_strength = if data[0] > 0 { 0xFF } else { 0 };
-                let mut rumble = ADAPTER_RUMBLE.lock().unwrap();
-                rumble[control as usize] = rumble_strength;
-                match ADAPTER.get().unwrap().set_rumble(*rumble) {
-                    Ok(_) => (),
-                    Err(e) => debug_print!(
-                        M64Message::Error,
-                        "Could not write rumble command to adapter: {:?}",
-                        e
-                    ),
-                }
+                ADAPTER_RUMBLE.lock().unwrap().game[control as usize] = rumble_strength;
+                RUMBLE_CHANGED.store(true, Ordering::Release);
             }
 
             data[32] = data_crc(data, 32);
@@ -396,10 +657,17 @@ pub extern "C" fn RomOpen() -> c_int {
     1
 }
 
-/// Currently unused, only needed to be a valid input plugin.
+/// Report the latency and the synchronous reads measured while the ROM was running.
 #[no_mangle]
 pub extern "C" fn RomClosed() {
     debug_print!(M64Message::Info, "RomClosed called");
+
+    report_latency();
+    for latency in &INPUT_LATENCY {
+        latency.reset();
+    }
+    POLL_STATS.load().report();
+    POLL_STATS.reset();
 }
 
 /// Currently unused, only needed to be a valid input plugin.
@@ -414,6 +682,258 @@ pub extern "C" fn SDL_KeyUp(_keymod: c_int, _keysym: c_int) {
     debug_print!(M64Message::Info, "SDL_KeyUp called");
 }
 
+/// Send the rumble strength of each controller to the adapter. A rumble pulse for feedback turns
+/// the rumble on fully, whatever the game has set.
+fn send_rumble(rumble: &Rumble) {
+    let mut strengths = rumble.game;
+    for (strength, pulse) in strengths.iter_mut().zip(rumble.pulse) {
+        if pulse {
+            *strength = 0xFF;
+        }
+    }
+
+    match ADAPTER.get().unwrap().set_rumble(strengths) {
+        Ok(_) => (),
+        Err(e) => debug_print!(
+            M64Message::Error,
+            "Could not write rumble command to adapter: {:?}",
+            e
+        ),
+    }
+}
+
+/// Store the calibrated notches for the given channel in the configuration file.
+fn save_notches(channel: Channel, notches: Notches) {
+    save_channel_config(channel, "calibration", |c| {
+        c.control_stick_notches = Some(notches)
+    });
+}
+
+/// Change the config of a channel in the config file. The config that is in use is not changed.
+fn save_channel_config(channel: Channel, what: &str, change: impl FnOnce(&mut ChannelConfig)) {
+    let Some(path) = CONFIG_PATH.get() else {
+        return;
+    };
+
+    let result = Config::read_from_file(path).and_then(|mut cfg| {
+        change(cfg.channel_mut(channel));
+        cfg.write_to_file(path)
+    });
+
+    if let Err(e) = result {
+        debug_print!(
+            M64Message::Error,
+            "Could not save {} to config file: {:?}",
+            what,
+            e
+        );
+    }
+}
+
+/// The processing of the states read from the adapter, before they are used by `GetKeys`.
+struct Poller {
+    connections: ConnectionDebouncer,
+    origins: OriginTracker,
+    filters: StickFilters,
+    calibrator: Calibrator,
+    tap_bindings: TapBindings,
+    latches: Latches,
+    turbo: Turbo,
+    samples: [Samples; 4],
+    /// Copies of `STICK_NOTCHES` and the rumble pulses, so that they are only locked when they
+    /// change.
+    notches: [Option<Notches>; 4],
+    pulse: [bool; 4],
+    report_latency_held: bool,
+    /// When `GetKeys` last tried to read the adapter in synchronous mode, whether the read
+    /// succeeded or not.
+    sync_read_at: Option<Instant>,
+}
+
+impl Poller {
+    const fn new() -> Self {
+        Self {
+            connections: ConnectionDebouncer::new(),
+            origins: OriginTracker::new(),
+            filters: StickFilters::new(),
+            calibrator: Calibrator::new(),
+            tap_bindings: TapBindings::new(),
+            latches: Latches::new(),
+            turbo: Turbo::new(),
+            samples: [Samples::new(); 4],
+            notches: [None; 4],
+            pulse: [false; 4],
+            report_latency_held: false,
+            sync_read_at: None,
+        }
+    }
+
+    /// Process a state read from the adapter, and store it for `GetKeys`.
+    fn process(&mut self, mut state: AdapterState, cfg: &Config, now: Instant) {
+        self.connections.update(&mut state, cfg);
+        self.origins.update(&mut state, cfg, now);
+        for i in 0..4 {
+            let channel = Channel::try_from(i).unwrap();
+            let channel_cfg = cfg.channel(channel);
+            state
+                .controller_state_mut(channel)
+                .transform_sticks(&channel_cfg.control_stick, &channel_cfg.c_stick);
+        }
+        self.filters.update(&mut state, cfg, now);
+
+        if let Some((channel, notches)) =
+            self.calibrator.update(&state, &cfg.hotkeys.calibrate, now)
+        {
+            self.notches[channel as usize] = Some(notches);
+            STICK_NOTCHES.lock().unwrap()[channel as usize] = Some(notches);
+            NOTCHES_CALIBRATED[channel as usize].store(true, Ordering::Release);
+        }
+        self.tap_bindings.update(&mut state, cfg, now);
+        self.latches.update(&mut state, cfg, now);
+        self.turbo.update(&mut state, cfg, now);
+
+        let report_latency_held = (0..4).any(|i| {
+            let s = state.controller_state(Channel::try_from(i).unwrap());
+            cfg.hotkeys.report_latency.is_held(&s)
+        });
+        if report_latency_held && !self.report_latency_held {
+            LATENCY_REPORT_REQUESTED.store(true, Ordering::Relaxed);
+        }
+        self.report_latency_held = report_latency_held;
+
+        let pulse = self.latches.rumble(now);
+        if self.pulse != pulse {
+            self.pulse = pulse;
+            ADAPTER_RUMBLE.lock().unwrap().pulse = pulse;
+            RUMBLE_CHANGED.store(true, Ordering::Release);
+        }
+
+        for (i, samples) in self.samples.iter_mut().enumerate() {
+            samples.add(&state.controller_state(Channel::try_from(i).unwrap()), now);
+        }
+        // Safety: the samples are only published while `POLLER` is locked
+        unsafe { SAMPLES.publish(&mut self.samples, self.notches, cfg.stick_sampling) };
+    }
+}
+
+static POLLER: Mutex<Poller> = Mutex::new(Poller::new());
+
+/// In synchronous mode, the adapter thread reads the adapter when the game has not read it for
+/// this long.
+const SYNC_IDLE_TIME: Duration = Duration::from_millis(50);
+
+/// Do the work that is deferred to the adapter thread, so that the emulator thread does not wait
+/// for it: reporting the latency, writing the rumble to the adapter, and saving calibrations and
+/// recorded macros to the config file.
+fn run_deferred_work() {
+    if LATENCY_REPORT_REQUESTED.swap(false, Ordering::Relaxed) {
+        report_latency();
+    }
+
+    if RUMBLE_CHANGED.swap(false, Ordering::Acquire) {
+        let rumble = *ADAPTER_RUMBLE.lock().unwrap();
+        send_rumble(&rumble);
+    }
+
+    for (i, calibrated) in NOTCHES_CALIBRATED.iter().enumerate() {
+        if calibrated.swap(false, Ordering::Acquire) {
+            let channel = Channel::try_from(i).unwrap();
+            if let Some(notches) = STICK_NOTCHES.lock().unwrap()[i] {
+                save_notches(channel, notches);
+            }
+        }
+    }
+
+    if MACRO_RECORDED.swap(false, Ordering::Acquire) {
+        // Safety: recorded macros are only read by the adapter thread
+        let (channel, recording) = unsafe { RECORDED_MACRO.read() };
+        save_channel_config(channel, "recorded macro", |c| {
+            c.recorded_macro = recording.to_steps()
+        });
+    }
+}
+
+/// How often the health of the adapter is logged.
+const HEALTH_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Read the adapter from `GetKeys`, if this is the first read of a frame.
+fn poll_synchronously(cfg: &Config) {
+    let Some(adapter) = ADAPTER.get() else {
+        return;
+    };
+
+    SYNC_POLLED.store(true, Ordering::Relaxed);
+    // In synchronous mode the adapter thread only holds the poller while it reads the adapter when
+    // the game has not read the controllers for a while. `GetKeys` does not wait for that read,
+    // and uses the state that the adapter thread has published instead.
+    let Ok(mut poller) = POLLER.try_lock() else {
+        return;
+    };
+    // Only the first read of a frame reads the adapter, also when that read timed out, so that a
+    // slow adapter does not make every port of the frame wait for it
+    if poller.sync_read_at.is_some_and(|t| t.elapsed() < FRAME_GAP) {
+        return;
+    }
+
+    let start = Instant::now();
+    let timeout = Duration::from_millis(cfg.sync_poll_timeout_ms as u64);
+    let result = adapter.read_timeout(timeout);
+    poller.sync_read_at = Some(Instant::now());
+    POLL_STATS.record_sync_poll(start.elapsed(), matches!(result, Ok(Some(_))));
+
+    if let Ok(Some(buf)) = result {
+        poller.process(AdapterState::from(buf), cfg, Instant::now());
+    }
+}
+
+/// Set the input delay of a channel in frames, overriding the config. The delay changes gradually,
+/// when it can be changed without losing or doubling button presses.
+pub fn set_input_delay(channel: Channel, frames: u8) {
+    INPUT_DELAY_OVERRIDE[channel as usize].store(frames.min(MAX_INPUT_DELAY), Ordering::Relaxed);
+}
+
+/// Get the current input delay of a channel in frames.
+pub fn input_delay(channel: Channel) -> u8 {
+    INPUT_DELAY[channel as usize].load(Ordering::Relaxed)
+}
+
+/// Get the measurements of the synchronous reads of the adapter.
+pub fn poll_stats() -> PollStats {
+    POLL_STATS.load()
+}
+
+/// Get the counters and statistics of the reads from the adapter, if it is connected.
+pub fn adapter_health() -> Option<AdapterHealth> {
+    ADAPTER.get().map(GcAdapter::health)
+}
+
+/// Get the latency of the input of a channel, measured since the ROM was opened.
+pub fn input_latency(channel: Channel) -> &'static ChannelLatency {
+    &INPUT_LATENCY[channel as usize]
+}
+
+/// Set up the plugin with `cfg` without the core or an adapter, so that `GetKeys` can be called on
+/// the states passed to `process_stub_state`. Only meant for the benchmarks.
+#[doc(hidden)]
+pub fn init_stub(cfg: Config) {
+    let _ = MAPPING_TABLE.set(MappingTable::new(&cfg));
+    let _ = CONFIG.set(cfg);
+}
+
+/// Process a state as if it was read from the adapter. Only meant for the benchmarks.
+#[doc(hidden)]
+pub fn process_stub_state(state: AdapterState) {
+    let cfg = CONFIG.get().unwrap();
+    POLLER.lock().unwrap().process(state, cfg, Instant::now());
+}
+
+/// Print the latency of the input of each channel through the debug callback.
+fn report_latency() {
+    for (i, latency) in INPUT_LATENCY.iter().enumerate() {
+        latency.report(i);
+    }
+}
+
 pub fn start_read_thread() {
     thread::spawn(move || {
         debug_print!(M64Message::Info, "Adapter thread started");
@@ -436,10 +956,53 @@ pub fn start_read_thread() {
 
         debug_print!(M64Message::Info, "Found a GameCube adapter");
 
+        let cfg = CONFIG.get().unwrap();
+        // The samples are kept, since `GetKeys` keeps track of the states it has taken from them
+        let mut poller = POLLER.lock().unwrap();
+        *poller = Poller {
+            samples: poller.samples,
+            notches: *STICK_NOTCHES.lock().unwrap(),
+            pulse: ADAPTER_RUMBLE.lock().unwrap().pulse,
+            ..Poller::new()
+        };
+        drop(poller);
+        let mut health_logged_at = Instant::now();
+        let mut sync_polled_at: Option<Instant> = None;
+
         while IS_INIT.load(Ordering::Acquire) {
-            match gc_adapter.read() {
-                Ok(buf) => *ADAPTER_STATE.lock().unwrap() = AdapterState::from(buf),
-                Err(e) => panic!("error while reading from adapter: {e:?}"),
+            // In synchronous mode the adapter is only read here when the game has not read it for
+            // a while, to keep hotkeys and timers running, e.g. while the emulator is paused
+            let poll = match cfg.polling_mode {
+                PollingMode::Background => true,
+                PollingMode::Synchronous => {
+                    if SYNC_POLLED.swap(false, Ordering::Relaxed) {
+                        sync_polled_at = Some(Instant::now());
+                    }
+                    sync_polled_at.is_none_or(|t| t.elapsed() >= SYNC_IDLE_TIME)
+                }
+            };
+
+            if poll {
+                // The adapter is only read while the poller is locked, here and in
+                // `poll_synchronously`, so that the reports are processed in the order they are read
+                let mut poller = POLLER.lock().unwrap();
+                // Without a valid report, the previous state is kept
+                let result = gc_adapter.read();
+                if let Ok(Some(buf)) = result {
+                    poller.process(AdapterState::from(buf), cfg, Instant::now());
+                }
+                drop(poller);
+
+                if let Err(e) = result {
+                    panic!("error while reading from adapter: {e:?}");
+                }
+            }
+
+            run_deferred_work();
+
+            if health_logged_at.elapsed() >= HEALTH_LOG_INTERVAL {
+                gc_adapter.health().report();
+                health_logged_at = Instant::now();
             }
 
             // Gives a polling rate of approx. 1000 Hz
@@ -449,3 +1012,31 @@ pub fn start_read_thread() {
         debug_print!(M64Message::Info, "Adapter thread stopped");
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use adapter::ControllerState;
+
+    #[test]
+    fn test_report_reset() {
+        let sample = |reset_held| Sample {
+            state: ControllerState::new(),
+            read_at: None,
+            pressed_at: None,
+            pressed: 0,
+            reset_held,
+        };
+        let start = N64Button::Start.bit_pattern();
+
+        let mut keys = BUTTONS { Value: start };
+        report_reset(&mut keys, &sample(false));
+        assert_eq!(start, unsafe { keys.Value });
+
+        report_reset(&mut keys, &sample(true));
+        unsafe {
+            assert_eq!(0, keys.__bindgen_anon_1.START_BUTTON());
+            assert_eq!(1, keys.__bindgen_anon_1.Reserved2());
+        }
+    }
+}
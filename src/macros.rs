@@ -0,0 +1,408 @@
+use crate::{
+    adapter::ControllerState,
+    config::{ChannelConfig, Hotkeys, MacroStep, N64Button},
+    debug::M64Message,
+};
+use std::time::{Duration, Instant};
+
+/// The buttons and stick position written by `GetKeys`.
+pub type Input = (u32, i8, i8);
+
+impl MacroStep {
+    fn input(&self) -> Input {
+        let buttons = self
+            .buttons
+            .iter()
+            .fold(0, |bits, b| bits | b.bit_pattern());
+        (buttons, self.x, self.y)
+    }
+
+    fn from_input((buttons, x, y): Input) -> Self {
+        Self {
+            buttons: N64Button::ALL
+                .into_iter()
+                .filter(|b| buttons & b.bit_pattern() != 0)
+                .collect(),
+            x,
+            y,
+            frames: Some(1),
+            ms: None,
+        }
+    }
+}
+
+/// The most steps a recorded macro can have. A step is added each time the recorded input changes.
+pub const MAX_RECORDED_STEPS: usize = 1024;
+
+/// A macro recorded with the record hotkey. The steps are stored in place, as the input of each
+/// step and the number of frames it is held, so that recording does not allocate in `GetKeys`.
+#[derive(Debug, Copy, Clone)]
+pub struct Recording {
+    steps: [(Input, u32); MAX_RECORDED_STEPS],
+    len: usize,
+}
+
+impl Recording {
+    pub const fn new() -> Self {
+        Self {
+            steps: [((0, 0, 0), 0); MAX_RECORDED_STEPS],
+            len: 0,
+        }
+    }
+
+    fn steps(&self) -> &[(Input, u32)] {
+        &self.steps[..self.len]
+    }
+
+    /// Record the input of a frame. Returns `false` if the recording is full.
+    fn record(&mut self, input: Input) -> bool {
+        match self.steps[..self.len].last_mut() {
+            Some((last, frames)) if *last == input => *frames += 1,
+            _ if self.len == MAX_RECORDED_STEPS => return false,
+            _ => {
+                self.steps[self.len] = (input, 1);
+                self.len += 1;
+            }
+        }
+        true
+    }
+
+    /// Get the steps of the recording, to save them to the config.
+    pub fn to_steps(&self) -> Vec<MacroStep> {
+        self.steps()
+            .iter()
+            .map(|&(input, frames)| MacroStep {
+                frames: Some(frames),
+                ..MacroStep::from_input(input)
+            })
+            .collect()
+    }
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The steps of a macro, either from the config or recorded.
+#[derive(Debug, Copy, Clone)]
+enum Steps<'a> {
+    Config(&'a [MacroStep]),
+    Recorded(&'a [(Input, u32)]),
+}
+
+impl Steps<'_> {
+    /// Get the input of a step, how many milliseconds it is held if it is timed, and how many
+    /// frames it is held otherwise.
+    fn get(&self, i: usize) -> Option<(Input, Option<u32>, u32)> {
+        match self {
+            Steps::Config(steps) => steps
+                .get(i)
+                .map(|step| (step.input(), step.ms, step.frames.unwrap_or(1))),
+            Steps::Recorded(steps) => steps.get(i).map(|&(input, frames)| (input, None, frames)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Source {
+    Config(usize),
+    Recorded,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Playback {
+    source: Source,
+    step: usize,
+    frames: u32,
+    since: Instant,
+}
+
+impl Playback {
+    fn new(source: Source, now: Instant) -> Self {
+        Self {
+            source,
+            step: 0,
+            frames: 0,
+            since: now,
+        }
+    }
+
+    /// Get the input of the current step, or `None` when the macro has finished.
+    fn next(&mut self, steps: Steps<'_>, now: Instant) -> Option<Input> {
+        loop {
+            let (input, ms, frames) = steps.get(self.step)?;
+            let done = match ms {
+                Some(ms) => now - self.since >= Duration::from_millis(ms as u64),
+                None => self.frames >= frames,
+            };
+            if !done {
+                self.frames += 1;
+                return Some(input);
+            }
+
+            self.step += 1;
+            self.frames = 0;
+            self.since = now;
+        }
+    }
+}
+
+/// Plays and records macros for one channel.
+///
+/// This runs in `GetKeys`, since macros replace the input written there. Steps timed in frames
+/// count the calls to `GetKeys`, which makes recorded macros replay exactly as they were played.
+#[derive(Debug)]
+pub struct MacroPlayer {
+    playback: Option<Playback>,
+    recording: Recording,
+    is_recording: bool,
+    /// The last recorded macro, if one has been recorded since the config was loaded.
+    recorded: Option<Recording>,
+    /// Which of the first `MAX_TRIGGERED_MACROS` macros of the config have their trigger held.
+    triggers_held: u32,
+    record_held: bool,
+    play_held: bool,
+}
+
+/// Only the first this many macros of the config can be triggered.
+const MAX_TRIGGERED_MACROS: usize = u32::BITS as usize;
+
+impl MacroPlayer {
+    pub const fn new() -> Self {
+        Self {
+            playback: None,
+            recording: Recording::new(),
+            is_recording: false,
+            recorded: None,
+            triggers_held: 0,
+            record_held: false,
+            play_held: false,
+        }
+    }
+
+    /// Check if a macro is being played.
+    pub fn is_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    fn steps<'a>(&'a self, source: Source, cfg: &'a ChannelConfig) -> Steps<'a> {
+        match source {
+            Source::Config(i) => Steps::Config(&cfg.macros[i].steps),
+            Source::Recorded => match &self.recorded {
+                Some(recorded) => Steps::Recorded(recorded.steps()),
+                None => Steps::Config(&cfg.recorded_macro),
+            },
+        }
+    }
+
+    /// Start macros and recordings from the state of the controller, and play or record the
+    /// input. Returns the input to write instead, and the new recording when it has finished.
+    pub fn update(
+        &mut self,
+        s: &ControllerState,
+        cfg: &ChannelConfig,
+        hotkeys: &Hotkeys,
+        input: Input,
+        now: Instant,
+    ) -> (Input, Option<&Recording>) {
+        let record_held = hotkeys.record_macro.is_held(s);
+        let record_pressed = record_held && !self.record_held;
+        self.record_held = record_held;
+        let play_held = hotkeys.play_macro.is_held(s);
+        let play_pressed = play_held && !self.play_held;
+        self.play_held = play_held;
+
+        let mut triggered = None;
+        for (i, m) in cfg.macros.iter().take(MAX_TRIGGERED_MACROS).enumerate() {
+            let bit = 1 << i;
+            let trigger_held = m.trigger.is_held(s);
+            if trigger_held && self.triggers_held & bit == 0 {
+                triggered = Some(Source::Config(i));
+            }
+            self.triggers_held = if trigger_held {
+                self.triggers_held | bit
+            } else {
+                self.triggers_held & !bit
+            };
+        }
+        if play_pressed {
+            triggered = Some(Source::Recorded);
+        }
+
+        let mut finish = false;
+        if record_pressed {
+            if self.is_recording {
+                finish = true;
+            } else {
+                debug_print!(M64Message::Status, "Recording a macro");
+                self.recording = Recording::new();
+                self.is_recording = true;
+            }
+        } else if let Some(source) = triggered {
+            self.playback = Some(Playback::new(source, now));
+        }
+
+        let output = match self.playback {
+            Some(mut playback) => {
+                let output = playback.next(self.steps(playback.source, cfg), now);
+                self.playback = output.map(|_| playback);
+                output.unwrap_or(input)
+            }
+            None => input,
+        };
+
+        // The hotkey is not recorded
+        if self.is_recording && !record_held && !self.recording.record(output) {
+            debug_print!(
+                M64Message::Warning,
+                "The macro is too long, only the first {} steps are recorded",
+                MAX_RECORDED_STEPS
+            );
+            finish = true;
+        }
+
+        if !finish {
+            return (output, None);
+        }
+
+        debug_print!(
+            M64Message::Status,
+            "Recorded a macro with {} steps",
+            self.recording.len
+        );
+        self.is_recording = false;
+        (output, Some(self.recorded.insert(self.recording)))
+    }
+}
+
+impl Default for MacroPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Chord, GcButton, Macro};
+
+    #[test]
+    fn test_macro() {
+        let cfg = ChannelConfig {
+            macros: vec![Macro {
+                trigger: Chord(vec![GcButton::Y]),
+                steps: vec![
+                    MacroStep {
+                        buttons: vec![N64Button::A],
+                        x: 0,
+                        y: 0,
+                        frames: Some(2),
+                        ms: None,
+                    },
+                    MacroStep {
+                        buttons: vec![],
+                        x: 80,
+                        y: 0,
+                        frames: None,
+                        ms: None,
+                    },
+                ],
+            }],
+            ..Default::default()
+        };
+        let hotkeys = Hotkeys::default();
+        let mut player = MacroPlayer::new();
+        let now = Instant::now();
+        let a = N64Button::A.bit_pattern();
+        let b = N64Button::B.bit_pattern();
+        let mut update = |y, input| {
+            let s = ControllerState {
+                y,
+                ..ControllerState::new()
+            };
+            player.update(&s, &cfg, &hotkeys, input, now).0
+        };
+
+        assert_eq!((b, 0, 0), update(false, (b, 0, 0)));
+        assert_eq!((a, 0, 0), update(true, (b, 0, 0)));
+        assert_eq!((a, 0, 0), update(true, (b, 0, 0)));
+        assert_eq!((0, 80, 0), update(false, (b, 0, 0)));
+        assert_eq!((b, 0, 0), update(false, (b, 0, 0)));
+    }
+
+    #[test]
+    fn test_record_macro() {
+        let cfg = ChannelConfig::default();
+        let hotkeys = Hotkeys {
+            record_macro: Chord(vec![GcButton::Z, GcButton::DPadLeft]),
+            ..Hotkeys::default()
+        };
+        let mut player = MacroPlayer::new();
+        let now = Instant::now();
+        let record = ControllerState {
+            z: true,
+            left: true,
+            ..ControllerState::new()
+        };
+        let idle = ControllerState::new();
+        let a = N64Button::A.bit_pattern();
+
+        // Recording is off by default
+        let defaults = Hotkeys::default();
+        player.update(&record, &cfg, &defaults, (0, 0, 0), now);
+        player.update(&idle, &cfg, &defaults, (a, 0, 0), now);
+        let (_, recorded) = player.update(&record, &cfg, &defaults, (0, 0, 0), now);
+        assert!(recorded.is_none());
+
+        player.update(&record, &cfg, &hotkeys, (0, 0, 0), now);
+        player.update(&idle, &cfg, &hotkeys, (a, 0, 0), now);
+        player.update(&idle, &cfg, &hotkeys, (a, 0, 0), now);
+        player.update(&idle, &cfg, &hotkeys, (0, 10, 20), now);
+        let (_, recorded) = player.update(&record, &cfg, &hotkeys, (0, 0, 0), now);
+        assert_eq!(
+            Some(vec![
+                MacroStep {
+                    buttons: vec![N64Button::A],
+                    x: 0,
+                    y: 0,
+                    frames: Some(2),
+                    ms: None,
+                },
+                MacroStep {
+                    buttons: vec![],
+                    x: 10,
+                    y: 20,
+                    frames: Some(1),
+                    ms: None,
+                },
+            ]),
+            recorded.map(Recording::to_steps)
+        );
+
+        // The recording is played back the same way
+        let play = ControllerState {
+            z: true,
+            right: true,
+            ..ControllerState::new()
+        };
+        let b = N64Button::B.bit_pattern();
+        assert_eq!(
+            (a, 0, 0),
+            player.update(&play, &cfg, &hotkeys, (b, 0, 0), now).0
+        );
+        assert_eq!(
+            (a, 0, 0),
+            player.update(&idle, &cfg, &hotkeys, (b, 0, 0), now).0
+        );
+        assert_eq!(
+            (0, 10, 20),
+            player.update(&idle, &cfg, &hotkeys, (b, 0, 0), now).0
+        );
+        assert_eq!(
+            (b, 0, 0),
+            player.update(&idle, &cfg, &hotkeys, (b, 0, 0), now).0
+        );
+    }
+}
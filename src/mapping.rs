@@ -0,0 +1,365 @@
+use crate::{
+    adapter::{Channel, ControllerState},
+    config::{Config, ControllerMapping, GcButton, GcTrigger, N64Button},
+};
+
+const L_BIT: u16 = 1 << 6;
+const R_BIT: u16 = 1 << 7;
+
+/// Get a bit for each pressed GC button, in the order of `GcButton::ALL`.
+pub fn button_mask(s: &ControllerState) -> u16 {
+    s.a as u16
+        | (s.b as u16) << 1
+        | (s.x as u16) << 2
+        | (s.y as u16) << 3
+        | (s.start as u16) << 4
+        | (s.z as u16) << 5
+        | if s.l { L_BIT } else { 0 }
+        | if s.r { R_BIT } else { 0 }
+        | (s.left as u16) << 8
+        | (s.right as u16) << 9
+        | (s.down as u16) << 10
+        | (s.up as u16) << 11
+}
+
+fn button_bit(button: GcButton) -> u16 {
+    let i = GcButton::ALL.iter().position(|&b| b == button).unwrap();
+    1 << i
+}
+
+/// Get the GC buttons of a channel that control the stick instead of pressing their mapping: the
+/// button and trigger of the walk modifier, the buttons of the fixed positions, and the D-pad when
+/// it moves the stick.
+fn stick_buttons(cfg: &Config, channel: Channel) -> u16 {
+    let channel_cfg = cfg.channel(channel);
+    let mut mask = channel_cfg.walk_modifier.button.map_or(0, button_bit);
+    mask |= match channel_cfg.walk_modifier.trigger {
+        Some(GcTrigger::Left) => L_BIT,
+        Some(GcTrigger::Right) => R_BIT,
+        None => 0,
+    };
+    for position in &channel_cfg.fixed_positions {
+        mask |= button_bit(position.button);
+    }
+    if channel_cfg.d_pad_to_stick.is_some() {
+        for b in [
+            GcButton::DPadLeft,
+            GcButton::DPadRight,
+            GcButton::DPadDown,
+            GcButton::DPadUp,
+        ] {
+            mask |= button_bit(b);
+        }
+    }
+    mask
+}
+
+/// A controller mapping, with the N64 bit pattern of each GC button looked up in advance.
+#[derive(Debug, Copy, Clone)]
+pub struct CompiledMapping {
+    /// The bit pattern of each GC button, in the order of `GcButton::ALL`. L and R have no bits,
+    /// since they are pressed by the analog triggers as well.
+    buttons: [u32; 12],
+    pub l: N64Button,
+    pub r: N64Button,
+    pub c_stick_left: N64Button,
+    pub c_stick_right: N64Button,
+    pub c_stick_down: N64Button,
+    pub c_stick_up: N64Button,
+}
+
+impl CompiledMapping {
+    fn new(mapping: &ControllerMapping) -> Self {
+        let mut buttons = [0; 12];
+        for (bits, &b) in buttons.iter_mut().zip(&GcButton::ALL) {
+            if b != GcButton::L && b != GcButton::R {
+                *bits = mapping.get(b).bit_pattern();
+            }
+        }
+
+        Self {
+            buttons,
+            l: mapping.l,
+            r: mapping.r,
+            c_stick_left: mapping.c_stick_left,
+            c_stick_right: mapping.c_stick_right,
+            c_stick_down: mapping.c_stick_down,
+            c_stick_up: mapping.c_stick_up,
+        }
+    }
+}
+
+/// The result of mapping the buttons of a controller.
+#[derive(Debug, Copy, Clone)]
+pub struct Mapped<'a> {
+    /// The N64 buttons pressed by chords and the digital GC buttons.
+    pub bits: u32,
+    /// The digital click and analog value of the triggers. Triggers that are part of a chord or
+    /// the modifier of a layer are released.
+    pub left: (bool, u8),
+    pub right: (bool, u8),
+    /// Whether the C-stick presses the C-buttons of the mapping. It does not while it presses the
+    /// D-pad instead.
+    pub c_stick: bool,
+    pub mapping: &'a CompiledMapping,
+}
+
+/// The chords, layers and controller mapping of the config, compiled to bit masks and bit
+/// patterns when the config is loaded, so that `GetKeys` does not go through the config.
+#[derive(Debug, Clone)]
+pub struct MappingTable {
+    /// The GC buttons of each chord, and the N64 buttons it presses.
+    chords: Vec<(u16, u32)>,
+    /// The modifier of each layer, and its mapping.
+    layers: Vec<(u16, CompiledMapping)>,
+    base: CompiledMapping,
+    /// The buttons of each channel that do not press their mapping.
+    stick_buttons: [u16; 4],
+    /// Whether the C-stick of each channel presses the C-buttons.
+    c_stick: [bool; 4],
+}
+
+impl MappingTable {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            chords: cfg
+                .chords
+                .iter()
+                .filter(|c| !c.buttons.0.is_empty())
+                .map(|c| {
+                    let mask = c.buttons.0.iter().fold(0, |m, &b| m | button_bit(b));
+                    (mask, c.press.bit_pattern())
+                })
+                .collect(),
+            layers: cfg
+                .layers
+                .iter()
+                .map(|l| (button_bit(l.modifier), CompiledMapping::new(&l.mapping)))
+                .collect(),
+            base: CompiledMapping::new(&cfg.controller_mapping),
+            stick_buttons: std::array::from_fn(|i| {
+                stick_buttons(cfg, Channel::try_from(i).unwrap())
+            }),
+            c_stick: std::array::from_fn(|i| {
+                cfg.channel(Channel::try_from(i).unwrap())
+                    .c_stick_to_d_pad
+                    .is_none()
+            }),
+        }
+    }
+
+    /// Map the buttons of a controller. Chords press their own N64 button instead of the mapping
+    /// of their buttons, and the modifier of a layer and the buttons that control the stick do not
+    /// press anything by themselves. A trigger that controls the stick is released however far it
+    /// is pressed.
+    pub fn map(&self, channel: Channel, s: &ControllerState) -> Mapped<'_> {
+        let pressed = button_mask(s);
+        let mut bits = 0;
+        let mut held = pressed & !self.stick_buttons[channel as usize];
+        for &(mask, press) in &self.chords {
+            if pressed & mask == mask {
+                bits |= press;
+                held &= !mask;
+            }
+        }
+
+        let (modifier, mapping) = self
+            .layers
+            .iter()
+            .find(|(modifier, _)| pressed & modifier != 0)
+            .map_or((0, &self.base), |(modifier, mapping)| (*modifier, mapping));
+        held &= !modifier;
+
+        let mut remaining = held;
+        while remaining != 0 {
+            bits |= mapping.buttons[remaining.trailing_zeros() as usize];
+            remaining &= remaining - 1;
+        }
+
+        let released = (pressed & !held) | (self.stick_buttons[channel as usize] & (L_BIT | R_BIT));
+        let trigger = |bit: u16, click: bool, value: u8| {
+            if released & bit != 0 {
+                (false, 0)
+            } else {
+                (click, value)
+            }
+        };
+
+        Mapped {
+            bits,
+            left: trigger(L_BIT, s.l, s.trigger_left),
+            right: trigger(R_BIT, s.r, s.trigger_right),
+            c_stick: self.c_stick[channel as usize],
+            mapping,
+        }
+    }
+
+    /// Get the N64 buttons that are pressed because the GC buttons in `pressed` are held, out of
+    /// the buttons held in `s`. Buttons that are not mapped to anything press nothing.
+    pub fn pressed_bits(&self, channel: Channel, s: &ControllerState, pressed: u16) -> u32 {
+        let mut without = *s;
+        for (i, b) in GcButton::ALL.iter().enumerate() {
+            if pressed & 1 << i != 0 {
+                b.set_pressed(&mut without, false);
+            }
+        }
+
+        let with = self.map(channel, s);
+        let without = self.map(channel, &without);
+        let mut bits = with.bits & !without.bits;
+        if with.left.0 && !without.left.0 {
+            bits |= with.mapping.l.bit_pattern();
+        }
+        if with.right.0 && !without.right.0 {
+            bits |= with.mapping.r.bit_pattern();
+        }
+        bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Chord, ChordMapping, Layer};
+
+    #[test]
+    fn test_button_mask() {
+        for (i, b) in GcButton::ALL.into_iter().enumerate() {
+            let mut s = ControllerState::new();
+            b.set_pressed(&mut s, true);
+            assert_eq!(1 << i, button_mask(&s));
+        }
+    }
+
+    #[test]
+    fn test_mapping_table() {
+        let mut cfg = Config {
+            chords: vec![ChordMapping {
+                buttons: Chord(vec![GcButton::X, GcButton::L]),
+                press: N64Button::Start,
+            }],
+            ..Config::default()
+        };
+        let mut mapping = cfg.controller_mapping.clone();
+        mapping.a = N64Button::CDown;
+        cfg.layers = vec![Layer {
+            modifier: GcButton::Z,
+            mapping,
+        }];
+        let table = MappingTable::new(&cfg);
+
+        // The same as mapping through the config
+        let mut s = ControllerState {
+            a: true,
+            b: true,
+            trigger_left: 100,
+            ..ControllerState::new()
+        };
+        let mapped = table.map(Channel::One, &s);
+        assert_eq!(cfg.controller_mapping.bit_pattern(&s), mapped.bits);
+        assert_eq!((false, 100), mapped.left);
+
+        s.x = true;
+        s.l = true;
+        let mapped = table.map(Channel::One, &s);
+        assert_eq!(
+            N64Button::Start.bit_pattern()
+                | N64Button::A.bit_pattern()
+                | N64Button::B.bit_pattern(),
+            mapped.bits
+        );
+        assert_eq!((false, 0), mapped.left);
+
+        s.z = true;
+        let mapped = table.map(Channel::One, &s);
+        assert_eq!(
+            N64Button::Start.bit_pattern()
+                | N64Button::CDown.bit_pattern()
+                | N64Button::B.bit_pattern(),
+            mapped.bits
+        );
+    }
+
+    #[test]
+    fn test_stick_buttons() {
+        let mut cfg = Config::default();
+        cfg.channel.two.walk_modifier.button = Some(GcButton::Y);
+        cfg.channel.two.d_pad_to_stick = Some(80);
+        cfg.channel.two.walk_modifier.trigger = Some(GcTrigger::Left);
+        let table = MappingTable::new(&cfg);
+
+        let s = ControllerState {
+            a: true,
+            y: true,
+            up: true,
+            ..ControllerState::new()
+        };
+        assert_eq!(N64Button::A.bit_pattern(), table.map(Channel::Two, &s).bits);
+        assert_eq!(
+            cfg.controller_mapping.bit_pattern(&s),
+            table.map(Channel::One, &s).bits
+        );
+
+        // The walk trigger is released, even when it is pressed past the threshold
+        let s = ControllerState {
+            l: true,
+            trigger_left: 255,
+            ..ControllerState::new()
+        };
+        assert_eq!((false, 0), table.map(Channel::Two, &s).left);
+        assert_eq!((true, 255), table.map(Channel::One, &s).left);
+    }
+
+    #[test]
+    fn test_c_stick_to_d_pad() {
+        let mut cfg = Config::default();
+        cfg.channel.three.c_stick_to_d_pad = Some(40);
+        let table = MappingTable::new(&cfg);
+
+        let s = ControllerState::new();
+        assert!(!table.map(Channel::Three, &s).c_stick);
+        assert!(table.map(Channel::One, &s).c_stick);
+    }
+
+    #[test]
+    fn test_pressed_bits() {
+        let mut cfg = Config {
+            chords: vec![ChordMapping {
+                buttons: Chord(vec![GcButton::X, GcButton::Y]),
+                press: N64Button::Start,
+            }],
+            ..Config::default()
+        };
+        cfg.channel.one.walk_modifier.button = Some(GcButton::B);
+        let table = MappingTable::new(&cfg);
+        let bit = |b: GcButton| button_bit(b);
+
+        let s = ControllerState {
+            a: true,
+            b: true,
+            l: true,
+            ..ControllerState::new()
+        };
+        assert_eq!(
+            N64Button::A.bit_pattern(),
+            table.pressed_bits(Channel::One, &s, bit(GcButton::A))
+        );
+        assert_eq!(
+            cfg.controller_mapping.l.bit_pattern(),
+            table.pressed_bits(Channel::One, &s, bit(GcButton::L))
+        );
+        // The walk modifier does not press anything
+        assert_eq!(0, table.pressed_bits(Channel::One, &s, bit(GcButton::B)));
+
+        // Completing a chord presses the chord
+        let s = ControllerState {
+            x: true,
+            y: true,
+            ..ControllerState::new()
+        };
+        assert_eq!(
+            N64Button::Start.bit_pattern(),
+            table.pressed_bits(Channel::One, &s, bit(GcButton::Y))
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use crate::{
+    adapter::{AdapterState, Channel, ControllerState, StickOrigin},
+    config::{Config, StickConfig},
+    debug::M64Message,
+};
+use std::time::{Duration, Instant};
+
+/// How quickly the origin follows the resting position of a stick, in seconds.
+const DRIFT_TIME_CONSTANT: f32 = 2.0;
+/// How quickly the measured noise follows the noise of a resting stick, in seconds.
+const NOISE_TIME_CONSTANT: f32 = 0.5;
+/// The adaptive deadzone is this many standard deviations of the noise.
+const NOISE_FACTOR: f32 = 4.0;
+/// Added to the adaptive deadzone, to account for the stick not resting at exactly the same
+/// position every time it is let go of.
+const DEADZONE_MARGIN: f32 = 2.0;
+const MIN_DEADZONE: f32 = 3.0;
+const MAX_DEADZONE: f32 = 64.0;
+/// A stick is considered to be resting when it is closer than this to its origin, or closer than
+/// the adaptive deadzone if that is larger.
+const MIN_IDLE_RADIUS: f32 = 4.0;
+/// The noise a stick is assumed to have before it has been measured.
+const INITIAL_VARIANCE: f32 = 1.0;
+/// A stick that rests outside of its idle radius, but closer than this to its origin, for
+/// `RESETTLE_TIME`, has its origin moved to where it rests.
+const MAX_RESETTLE_DISTANCE: f32 = 24.0;
+/// How long, in seconds, a stick has to rest away from its origin before the origin is moved.
+const RESETTLE_TIME: f32 = 3.0;
+/// How often the adaptive deadzones are reported at most.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// The adaptive deadzones are only reported again when an origin or a deadzone has changed by at
+/// least this much, so that a value that goes back and forth when it is rounded is not reported.
+const MIN_REPORTED_CHANGE: u8 = 2;
+
+#[derive(Debug, Copy, Clone)]
+struct TrackedStick {
+    x: f32,
+    y: f32,
+    variance: f32,
+    /// Where the stick has been resting outside of its idle radius, and for how long.
+    settling: Option<(f32, f32, f32)>,
+}
+
+impl TrackedStick {
+    fn new(x: u8, y: u8) -> Self {
+        Self {
+            x: x as f32,
+            y: y as f32,
+            variance: INITIAL_VARIANCE,
+            settling: None,
+        }
+    }
+
+    /// Follow the resting position of the stick, and measure its noise. Samples where the stick is
+    /// not resting are ignored, unless the stick keeps resting close to the same position near its
+    /// origin, in which case tracking starts over from that position.
+    fn track(&mut self, x: u8, y: u8, dt: f32) {
+        let (dx, dy) = (x as f32 - self.x, y as f32 - self.y);
+        let distance_squared = dx * dx + dy * dy;
+        let idle_radius = (NOISE_FACTOR * self.variance.sqrt()).max(MIN_IDLE_RADIUS);
+        if distance_squared > idle_radius * idle_radius {
+            self.settle(x as f32, y as f32, distance_squared, dt);
+            return;
+        }
+        self.settling = None;
+
+        let drift = 1.0 - (-dt / DRIFT_TIME_CONSTANT).exp();
+        self.x += drift * dx;
+        self.y += drift * dy;
+
+        let noise = 1.0 - (-dt / NOISE_TIME_CONSTANT).exp();
+        self.variance += noise * (distance_squared - self.variance);
+    }
+
+    fn settle(&mut self, x: f32, y: f32, distance_squared: f32, dt: f32) {
+        if distance_squared > MAX_RESETTLE_DISTANCE * MAX_RESETTLE_DISTANCE {
+            self.settling = None;
+            return;
+        }
+
+        self.settling = match self.settling {
+            Some((sx, sy, time))
+                if (x - sx).powi(2) + (y - sy).powi(2) <= MIN_IDLE_RADIUS * MIN_IDLE_RADIUS =>
+            {
+                Some((sx, sy, time + dt))
+            }
+            _ => Some((x, y, 0.0)),
+        };
+        if let Some((sx, sy, time)) = self.settling {
+            if time >= RESETTLE_TIME {
+                *self = Self {
+                    x: sx,
+                    y: sy,
+                    ..Self::new(0, 0)
+                };
+            }
+        }
+    }
+
+    fn origin(&self) -> StickOrigin {
+        StickOrigin {
+            x: self.x.round() as u8,
+            y: self.y.round() as u8,
+        }
+    }
+
+    fn deadzone(&self) -> u8 {
+        (NOISE_FACTOR * self.variance.sqrt() + DEADZONE_MARGIN).clamp(MIN_DEADZONE, MAX_DEADZONE)
+            as u8
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Tracked {
+    stick: TrackedStick,
+    substick: TrackedStick,
+    reported: Option<(Instant, [u8; 6])>,
+}
+
+impl Tracked {
+    fn record(state: &ControllerState) -> Self {
+        Self {
+            stick: TrackedStick::new(state.stick_x, state.stick_y),
+            substick: TrackedStick::new(state.substick_x, state.substick_y),
+            reported: None,
+        }
+    }
+}
+
+/// Keeps track of the neutral position of the sticks of each controller.
+///
+/// The origin is recorded when a controller is connected, and recorded again while L, R and Start
+/// are held, like on an N64 controller. The sticks should therefore not be touched while
+/// connecting a controller or resetting the origin.
+///
+/// For sticks with an adaptive deadzone the origin also follows the resting position of the stick,
+/// and the deadzone is sized from the noise of the stick while it is resting.
+#[derive(Debug)]
+pub struct OriginTracker {
+    channels: [Option<Tracked>; 4],
+    /// Whether L, R and Start were held in the last update, so that a reset is only logged once.
+    reset_held: [bool; 4],
+    last_update: Option<Instant>,
+}
+
+impl OriginTracker {
+    pub const fn new() -> Self {
+        Self {
+            channels: [None; 4],
+            reset_held: [false; 4],
+            last_update: None,
+        }
+    }
+
+    /// Record or track the origins, and store them in the controller states.
+    pub fn update(&mut self, state: &mut AdapterState, cfg: &Config, now: Instant) {
+        let dt = self
+            .last_update
+            .map_or(0.0, |t| now.duration_since(t).as_secs_f32().min(0.1));
+        self.last_update = Some(now);
+
+        for (i, tracked) in self.channels.iter_mut().enumerate() {
+            let channel = Channel::try_from(i).unwrap();
+            let channel_cfg = cfg.channel(channel);
+            let s = state.controller_state_mut(channel);
+            if !s.is_connected() {
+                *tracked = None;
+                self.reset_held[i] = false;
+                continue;
+            }
+
+            let reset_held = s.is_reset_held();
+            let reset_pressed = reset_held && !self.reset_held[i];
+            self.reset_held[i] = reset_held;
+
+            let t = match tracked {
+                Some(t) if !reset_held => t,
+                _ => {
+                    // The origin is recorded on every update while the buttons are held, but only
+                    // logged when the controller is connected and when the buttons are pressed
+                    if tracked.is_none() || reset_pressed {
+                        debug_print!(
+                            M64Message::Status,
+                            "Recorded origin for controller {}: control stick ({}, {}), C-stick ({}, {})",
+                            i + 1,
+                            s.stick_x,
+                            s.stick_y,
+                            s.substick_x,
+                            s.substick_y
+                        );
+                    }
+
+                    tracked.insert(Tracked::record(s))
+                }
+            };
+
+            let adaptive = |stick_cfg: &StickConfig, stick: &mut TrackedStick, x, y| {
+                if stick_cfg.adaptive_deadzone {
+                    stick.track(x, y, dt);
+                    Some(stick.deadzone())
+                } else {
+                    None
+                }
+            };
+
+            s.stick_deadzone = adaptive(
+                &channel_cfg.control_stick,
+                &mut t.stick,
+                s.stick_x,
+                s.stick_y,
+            );
+            s.substick_deadzone = adaptive(
+                &channel_cfg.c_stick,
+                &mut t.substick,
+                s.substick_x,
+                s.substick_y,
+            );
+            s.stick_origin = t.stick.origin();
+            s.substick_origin = t.substick.origin();
+
+            if s.stick_deadzone.is_none() && s.substick_deadzone.is_none() {
+                continue;
+            }
+
+            let learned = [
+                s.stick_origin.x,
+                s.stick_origin.y,
+                s.substick_origin.x,
+                s.substick_origin.y,
+                s.stick_deadzone.unwrap_or(0),
+                s.substick_deadzone.unwrap_or(0),
+            ];
+            let report = match t.reported {
+                Some((at, values)) => {
+                    now - at >= REPORT_INTERVAL
+                        && values
+                            .iter()
+                            .zip(&learned)
+                            .any(|(a, b)| a.abs_diff(*b) >= MIN_REPORTED_CHANGE)
+                }
+                None => true,
+            };
+            if report {
+                debug_print!(
+                    M64Message::Status,
+                    "Adaptive deadzone for controller {}: control stick origin ({:.1}, {:.1}), deadzone {}, C-stick origin ({:.1}, {:.1}), deadzone {}",
+                    i + 1,
+                    t.stick.x,
+                    t.stick.y,
+                    t.stick.deadzone(),
+                    t.substick.x,
+                    t.substick.y,
+                    t.substick.deadzone()
+                );
+                t.reported = Some((now, learned));
+            }
+        }
+    }
+}
+
+impl Default for OriginTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracked_stick() {
+        let mut stick = TrackedStick::new(128, 128);
+
+        // Ten seconds of a stick resting at (130, 126) with one unit of noise, at 1000 Hz
+        for i in 0..10_000 {
+            let noise = [0, 1, 0, -1][i % 4];
+            stick.track((130 + noise) as u8, (126 - noise) as u8, 0.001);
+        }
+        assert_eq!(StickOrigin { x: 130, y: 126 }, stick.origin());
+        assert_eq!(6, stick.deadzone());
+
+        // Moving the stick does not move the origin
+        for _ in 0..1000 {
+            stick.track(200, 126, 0.001);
+        }
+        assert_eq!(StickOrigin { x: 130, y: 126 }, stick.origin());
+
+        // A stick that comes to rest somewhere else near the origin is tracked from there
+        for _ in 0..4000 {
+            stick.track(140, 126, 0.001);
+        }
+        assert_eq!(StickOrigin { x: 140, y: 126 }, stick.origin());
+    }
+
+    #[test]
+    fn test_reset_origin() {
+        let cfg = Config::default();
+        let mut tracker = OriginTracker::new();
+        let now = Instant::now();
+        let mut update = |stick_x, reset| {
+            let mut state = AdapterState::new();
+            state.controller_0 = ControllerState {
+                status: 0x10,
+                stick_x,
+                stick_y: 128,
+                l: reset,
+                r: reset,
+                start: reset,
+                ..ControllerState::new()
+            };
+            tracker.update(&mut state, &cfg, now);
+            state.controller_0.stick_origin.x
+        };
+
+        // Recorded on connect, and kept while the stick moves
+        assert_eq!(130, update(130, false));
+        assert_eq!(130, update(200, false));
+
+        // Recorded again on every report while L, R and Start are held
+        assert_eq!(140, update(140, true));
+        assert_eq!(141, update(141, true));
+        assert_eq!(141, update(150, false));
+    }
+}
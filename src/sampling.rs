@@ -0,0 +1,644 @@
+use crate::{
+    adapter::{Channel, ControllerState},
+    buffer::TripleBuffer,
+    config::{GcButton, StickSampling},
+    mapping::button_mask,
+    stick::Notches,
+};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Reads that are further apart than this are considered to be in different frames.
+pub const FRAME_GAP: Duration = Duration::from_millis(4);
+
+/// How many states of a controller are kept separately until `GetKeys` takes them. Older states,
+/// e.g. while the emulator is paused, are combined into one.
+const HISTORY_LEN: usize = 64;
+
+#[derive(Debug, Copy, Clone)]
+struct StickSamples {
+    sum: (u64, u64),
+    extremum: (u8, u8),
+    extremum_distance: i32,
+}
+
+impl StickSamples {
+    const fn new() -> Self {
+        Self {
+            sum: (0, 0),
+            extremum: (0, 0),
+            extremum_distance: -1,
+        }
+    }
+
+    fn add(&mut self, x: u8, y: u8, (dx, dy): (i8, i8)) {
+        self.sum.0 += x as u64;
+        self.sum.1 += y as u64;
+
+        let distance = dx as i32 * dx as i32 + dy as i32 * dy as i32;
+        if distance > self.extremum_distance {
+            self.extremum = (x, y);
+            self.extremum_distance = distance;
+        }
+    }
+
+    fn sample(&self, sampling: StickSampling, count: u32, latest: (u8, u8)) -> (u8, u8) {
+        match sampling {
+            StickSampling::Latest => latest,
+            StickSampling::Average => {
+                let count = count as u64;
+                (
+                    ((self.sum.0 + count / 2) / count) as u8,
+                    ((self.sum.1 + count / 2) / count) as u8,
+                )
+            }
+            StickSampling::Extremum => self.extremum,
+        }
+    }
+}
+
+/// A state of a controller as seen by `GetKeys`.
+#[derive(Debug, Copy, Clone)]
+pub struct Sample {
+    pub state: ControllerState,
+    /// When the latest of the states that the sample was made from was read from the adapter.
+    pub read_at: Option<Instant>,
+    /// When the first button that was pressed in the states was read, to measure how long the
+    /// press takes to reach the game.
+    pub pressed_at: Option<Instant>,
+    /// The GC buttons that were pressed in the states, in the order of `GcButton::ALL`. Buttons
+    /// pressed again by turbo are left out.
+    pub pressed: u16,
+    /// Whether L, R and Start are held in the latest of the states. This is not combined, so that
+    /// the buttons do not reset the origin when they were pressed at different moments.
+    pub reset_held: bool,
+}
+
+impl Sample {
+    const fn new() -> Self {
+        Self {
+            state: ControllerState::new(),
+            read_at: None,
+            pressed_at: None,
+            pressed: 0,
+            reset_held: false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    state: ControllerState,
+    read_at: Instant,
+    pressed: u16,
+}
+
+/// Report a button as pressed if it is pressed in either state, and a trigger as pressed as far as
+/// it is pressed in either state.
+fn combine_buttons(c: &mut ControllerState, s: &ControllerState) {
+    c.a |= s.a;
+    c.b |= s.b;
+    c.x |= s.x;
+    c.y |= s.y;
+    c.left |= s.left;
+    c.right |= s.right;
+    c.down |= s.down;
+    c.up |= s.up;
+    c.start |= s.start;
+    c.z |= s.z;
+    c.r |= s.r;
+    c.l |= s.l;
+    c.trigger_left = c.trigger_left.max(s.trigger_left);
+    c.trigger_right = c.trigger_right.max(s.trigger_right);
+    c.bound_buttons |= s.bound_buttons;
+}
+
+/// States of a controller combined into one.
+#[derive(Debug, Copy, Clone)]
+struct Combined {
+    /// The buttons and triggers of the states.
+    buttons: ControllerState,
+    pressed_at: Option<Instant>,
+    pressed: u16,
+    stick: StickSamples,
+    substick: StickSamples,
+    count: u32,
+}
+
+impl Combined {
+    const fn new() -> Self {
+        Self {
+            buttons: ControllerState::new(),
+            pressed_at: None,
+            pressed: 0,
+            stick: StickSamples::new(),
+            substick: StickSamples::new(),
+            count: 0,
+        }
+    }
+
+    /// Add a state, which has to be newer than the states that have been added before.
+    fn add(&mut self, e: &Entry) {
+        let s = &e.state;
+        combine_buttons(&mut self.buttons, s);
+        if e.pressed != 0 && self.pressed_at.is_none() {
+            self.pressed_at = Some(e.read_at);
+        }
+        self.pressed |= e.pressed;
+        self.stick.add(s.stick_x, s.stick_y, s.stick());
+        self.substick.add(s.substick_x, s.substick_y, s.substick());
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// Get the sample of the combined states, where `latest` is the newest of the states.
+    fn sample(&self, latest: &Sample, sampling: StickSampling) -> Sample {
+        let mut combined = *latest;
+        let c = &mut combined.state;
+        combine_buttons(c, &self.buttons);
+        combined.pressed_at = self.pressed_at;
+        combined.pressed = self.pressed;
+
+        // Combining the states would hide the releases of turbo, so buttons that are still held
+        // with turbo are released if they are released now
+        let turbo = latest.state.turbo_buttons;
+        if turbo != 0 {
+            for (i, b) in GcButton::ALL.iter().enumerate() {
+                if turbo & 1 << i != 0 && !b.is_pressed(&latest.state) {
+                    b.set_pressed(c, false);
+                }
+            }
+        }
+
+        if self.count > 0 {
+            let latest = (c.stick_x, c.stick_y);
+            (c.stick_x, c.stick_y) = self.stick.sample(sampling, self.count, latest);
+            let latest = (c.substick_x, c.substick_y);
+            (c.substick_x, c.substick_y) = self.substick.sample(sampling, self.count, latest);
+        }
+
+        combined
+    }
+}
+
+/// The states of a controller read from the adapter that `GetKeys` has not taken yet.
+///
+/// `GetKeys` is only called about once per frame, while the adapter is read about every
+/// millisecond, so a button that is pressed and released between two frames would otherwise be
+/// lost. Buttons are therefore reported as pressed if they were pressed in any of the states, except
+/// for buttons held with turbo, the triggers report how far they were pressed at most, and the
+/// sticks are sampled as configured.
+///
+/// Each state is numbered by a generation. `GetKeys` reports the generation it has taken, and the
+/// states up to that generation are left out of the states published after that. States that are
+/// not taken before they drop out of the history are kept combined until `GetKeys` takes them.
+#[derive(Debug, Copy, Clone)]
+pub struct Samples {
+    history: [Option<Entry>; HISTORY_LEN],
+    /// The generation of the newest state.
+    generation: u64,
+    latest: Sample,
+    /// The states that dropped out of the history since the `evicted_since` generation was taken.
+    evicted: Combined,
+    evicted_since: u64,
+}
+
+impl Samples {
+    pub const fn new() -> Self {
+        Self {
+            history: [None; HISTORY_LEN],
+            generation: 0,
+            latest: Sample::new(),
+            evicted: Combined::new(),
+            evicted_since: 0,
+        }
+    }
+
+    pub fn add(&mut self, s: &ControllerState, now: Instant) {
+        let last = &self.latest.state;
+        let pressed = button_mask(s) & !button_mask(last) & !last.turbo_buttons;
+
+        self.generation += 1;
+        let slot = &mut self.history[self.generation as usize % HISTORY_LEN];
+        let evicted_generation = self.generation.saturating_sub(HISTORY_LEN as u64);
+        if let Some(e) = slot {
+            if evicted_generation > self.evicted_since {
+                self.evicted.add(e);
+            }
+        }
+        *slot = Some(Entry {
+            state: *s,
+            read_at: now,
+            pressed,
+        });
+        self.latest = Sample {
+            state: *s,
+            read_at: Some(now),
+            pressed_at: None,
+            pressed: 0,
+            reset_held: s.is_reset_held(),
+        };
+    }
+
+    /// Combine the states that are newer than the `taken` generation. This is called after every
+    /// state that is added, so that the states that drop out of the history are only kept if they
+    /// have not been taken.
+    pub fn publish(&mut self, taken: u64, sampling: StickSampling) -> Published {
+        if taken != self.evicted_since {
+            // The states that had dropped out of the history have been taken
+            self.evicted = Combined::new();
+            self.evicted_since = taken;
+        }
+
+        let oldest = self.generation.saturating_sub(HISTORY_LEN as u64 - 1);
+        let mut combined = self.evicted;
+        for generation in (taken + 1).max(oldest)..=self.generation {
+            if let Some(e) = &self.history[generation as usize % HISTORY_LEN] {
+                combined.add(e);
+            }
+        }
+
+        Published {
+            latest: self.latest,
+            combined: combined.sample(&self.latest, sampling),
+            generation: self.generation,
+            since: taken,
+        }
+    }
+}
+
+impl Default for Samples {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The states of a controller as published to `GetKeys`.
+#[derive(Debug, Copy, Clone)]
+pub struct Published {
+    latest: Sample,
+    combined: Sample,
+    generation: u64,
+    /// The generation that had been taken when the states were combined.
+    since: u64,
+}
+
+impl Published {
+    const fn new() -> Self {
+        Self {
+            latest: Sample::new(),
+            combined: Sample::new(),
+            generation: 0,
+            since: 0,
+        }
+    }
+
+    /// Take the combined state, if it has new states and none that were taken before. Otherwise
+    /// the latest state is returned, and the new states are taken from a later publication.
+    fn take(&self, taken: &AtomicU64) -> Sample {
+        let t = taken.load(Ordering::Relaxed);
+        if self.generation > t && self.since == t {
+            taken.store(self.generation, Ordering::Release);
+            self.combined
+        } else {
+            self.latest
+        }
+    }
+}
+
+/// Everything that `GetKeys` needs from the adapter thread.
+#[derive(Debug, Copy, Clone)]
+pub struct Frame {
+    pub samples: [Published; 4],
+    pub notches: [Option<Notches>; 4],
+}
+
+impl Frame {
+    pub fn any_connected(&self) -> bool {
+        self.samples.iter().any(|p| p.latest.state.is_connected())
+    }
+}
+
+/// Passes the states read from the adapter to `GetKeys` without locking, so that the adapter
+/// thread and `GetKeys` never wait for each other.
+#[derive(Debug)]
+pub struct SampleChannel {
+    frames: TripleBuffer<Frame>,
+    /// The generation that `GetKeys` has taken for each controller.
+    taken: [AtomicU64; 4],
+}
+
+impl SampleChannel {
+    pub const fn new() -> Self {
+        Self {
+            frames: TripleBuffer::new(Frame {
+                samples: [Published::new(); 4],
+                notches: [None; 4],
+            }),
+            taken: [const { AtomicU64::new(0) }; 4],
+        }
+    }
+
+    /// Publish the states that `GetKeys` has not taken yet.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may publish at a time.
+    pub unsafe fn publish(
+        &self,
+        samples: &mut [Samples; 4],
+        notches: [Option<Notches>; 4],
+        sampling: StickSampling,
+    ) {
+        let mut frame = Frame {
+            samples: [Published::new(); 4],
+            notches,
+        };
+        for ((published, samples), taken) in frame.samples.iter_mut().zip(samples).zip(&self.taken)
+        {
+            *published = samples.publish(taken.load(Ordering::Acquire), sampling);
+        }
+
+        self.frames.publish(frame);
+    }
+
+    /// Get the newest published frame.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may read at a time.
+    pub unsafe fn read(&self) -> Frame {
+        self.frames.read()
+    }
+
+    /// Get the combined state of a controller from a frame, and start combining the states that
+    /// are read after this. If there are no new states, the latest state is returned again.
+    pub fn take(&self, channel: Channel, frame: &Frame) -> Sample {
+        frame.samples[channel as usize].take(&self.taken[channel as usize])
+    }
+}
+
+impl Default for SampleChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A snapshot of all controllers that is taken once per frame, so that every port sees the
+/// controllers at the same moment.
+///
+/// A new snapshot is taken by the first read of a frame, which is a read of a port that has
+/// already been read from the snapshot, or a read more than a few milliseconds after the snapshot
+/// was taken. The frame callback of the core is not used for this, since the core only has room
+/// for one frame callback, which is used by the frontend.
+#[derive(Debug)]
+pub struct FrameSnapshot {
+    samples: [Sample; 4],
+    taken: Option<Instant>,
+    /// The ports that have been read from the snapshot, as a bit for each channel.
+    read: u8,
+}
+
+impl FrameSnapshot {
+    pub const fn new() -> Self {
+        Self {
+            samples: [Sample::new(); 4],
+            taken: None,
+            read: 0,
+        }
+    }
+
+    pub fn read(
+        &mut self,
+        channel: Channel,
+        frame: &Frame,
+        samples: &SampleChannel,
+        now: Instant,
+    ) -> Sample {
+        let bit = 1 << channel as u8;
+        if self.read & bit != 0 || self.taken.is_none_or(|t| now - t >= FRAME_GAP) {
+            for (i, sample) in self.samples.iter_mut().enumerate() {
+                *sample = samples.take(Channel::try_from(i).unwrap(), frame);
+            }
+            self.taken = Some(now);
+            self.read = 0;
+        }
+
+        self.read |= bit;
+        self.samples[channel as usize]
+    }
+}
+
+impl Default for FrameSnapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples() {
+        let mut samples = Samples::new();
+        let taken = AtomicU64::new(0);
+        let now = Instant::now();
+        let state = |a, stick_x| ControllerState {
+            status: 0x10,
+            a,
+            stick_x,
+            stick_y: 128,
+            ..ControllerState::new()
+        };
+        let take = |samples: &mut Samples, sampling| {
+            samples
+                .publish(taken.load(Ordering::Relaxed), sampling)
+                .take(&taken)
+        };
+
+        // A short tap is reported once
+        samples.add(&state(false, 128), now);
+        samples.add(&state(true, 208), now);
+        samples.add(&state(false, 138), now);
+        let s = take(&mut samples, StickSampling::Latest);
+        assert!(s.state.a);
+        assert_eq!(Some(now), s.pressed_at);
+        assert_eq!(1, s.pressed);
+        assert_eq!(138, s.state.stick_x);
+        let s = take(&mut samples, StickSampling::Latest);
+        assert!(!s.state.a);
+        assert_eq!(None, s.pressed_at);
+        assert_eq!(0, s.pressed);
+
+        samples.add(&state(false, 128), now);
+        samples.add(&state(false, 208), now);
+        samples.add(&state(false, 138), now);
+        let published = samples.publish(taken.load(Ordering::Relaxed), StickSampling::Average);
+        assert_eq!(158, published.combined.state.stick_x);
+        assert_eq!(
+            208,
+            take(&mut samples, StickSampling::Extremum).state.stick_x
+        );
+
+        // L, R and Start only reset when they are held at the same time
+        let buttons = |l, r, start| ControllerState {
+            status: 0x10,
+            l,
+            r,
+            start,
+            ..ControllerState::new()
+        };
+        samples.add(&buttons(true, false, false), now);
+        samples.add(&buttons(false, true, false), now);
+        samples.add(&buttons(false, false, true), now);
+        let s = take(&mut samples, StickSampling::Latest);
+        assert!(s.state.is_reset_held());
+        assert!(!s.reset_held);
+        samples.add(&buttons(true, true, true), now);
+        assert!(take(&mut samples, StickSampling::Latest).reset_held);
+    }
+
+    #[test]
+    fn test_evicted_states() {
+        let mut samples = Samples::new();
+        let taken = AtomicU64::new(0);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let state = |a| ControllerState {
+            status: 0x10,
+            a,
+            ..ControllerState::new()
+        };
+        // Like the adapter thread, which publishes after every state
+        let add = |samples: &mut Samples, a, ms| {
+            samples.add(&state(a), at(ms));
+            samples.publish(taken.load(Ordering::Relaxed), StickSampling::Average)
+        };
+
+        // A tap that dropped out of the history while the game was not reading is still reported
+        add(&mut samples, true, 0);
+        for ms in 1..200 {
+            add(&mut samples, false, ms);
+        }
+        let published = add(&mut samples, false, 200);
+        let s = published.take(&taken);
+        assert!(s.state.a);
+        assert_eq!(Some(at(0)), s.pressed_at);
+
+        // And only once, while the next tap is reported as well
+        for ms in 201..400 {
+            add(&mut samples, ms == 300, ms);
+        }
+        let published = add(&mut samples, false, 400);
+        assert_eq!(Some(at(300)), published.take(&taken).pressed_at);
+        let published = add(&mut samples, false, 401);
+        assert!(!published.take(&taken).state.a);
+    }
+
+    #[test]
+    fn test_turbo_buttons() {
+        let mut samples = Samples::new();
+        let taken = AtomicU64::new(0);
+        let now = Instant::now();
+        let state = |a, turbo_buttons| ControllerState {
+            status: 0x10,
+            a,
+            turbo_buttons,
+            ..ControllerState::new()
+        };
+        let take = |samples: &mut Samples| {
+            samples
+                .publish(taken.load(Ordering::Relaxed), StickSampling::Latest)
+                .take(&taken)
+        };
+
+        // A released by turbo since the last read is released
+        samples.add(&state(true, 1), now);
+        samples.add(&state(false, 1), now);
+        assert!(!take(&mut samples).state.a);
+        // Pressing it again is not counted as a press
+        samples.add(&state(true, 1), now);
+        let s = take(&mut samples);
+        assert!(s.state.a);
+        assert_eq!(0, s.pressed);
+
+        // A tap with turbo is still reported
+        samples.add(&state(false, 1), now);
+        samples.add(&state(true, 1), now);
+        samples.add(&state(false, 0), now);
+        assert!(take(&mut samples).state.a);
+    }
+
+    #[test]
+    fn test_stale_publication() {
+        let mut samples = Samples::new();
+        let taken = AtomicU64::new(0);
+        let now = Instant::now();
+        let a = ControllerState {
+            status: 0x10,
+            a: true,
+            ..ControllerState::new()
+        };
+        let idle = ControllerState {
+            status: 0x10,
+            ..ControllerState::new()
+        };
+
+        samples.add(&a, now);
+        let first = samples.publish(0, StickSampling::Latest);
+        // Published before the first publication was taken
+        samples.add(&idle, now);
+        let stale = samples.publish(0, StickSampling::Latest);
+
+        assert!(first.take(&taken).state.a);
+        // The press is not taken twice, and the new state is taken from the next publication
+        assert!(!stale.take(&taken).state.a);
+        assert_eq!(1, taken.load(Ordering::Relaxed));
+        let next = samples.publish(taken.load(Ordering::Relaxed), StickSampling::Latest);
+        assert!(!next.take(&taken).state.a);
+        assert_eq!(2, taken.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_frame_snapshot() {
+        let channel = SampleChannel::new();
+        let mut samples = [Samples::new(); 4];
+        let mut snapshot = FrameSnapshot::new();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let state = |a| ControllerState {
+            status: 0x10,
+            a,
+            ..ControllerState::new()
+        };
+        let publish = |samples: &mut [Samples; 4]| unsafe {
+            channel.publish(samples, [None; 4], StickSampling::Latest);
+            channel.read()
+        };
+
+        samples[0].add(&state(true), at(0));
+        samples[3].add(&state(true), at(0));
+        let frame = publish(&mut samples);
+        let read = snapshot.read(Channel::One, &frame, &channel, at(0));
+        assert!(read.state.a);
+
+        // Port 4 sees the same moment as port 1
+        samples[0].add(&state(false), at(0));
+        samples[3].add(&state(false), at(0));
+        let frame = publish(&mut samples);
+        let read = snapshot.read(Channel::Four, &frame, &channel, at(0));
+        assert!(read.state.a);
+
+        // Reading port 1 again starts the next frame, even when it follows quickly
+        let read = snapshot.read(Channel::One, &frame, &channel, at(1));
+        assert!(!read.state.a);
+
+        // As does a read after a while
+        samples[3].add(&state(true), at(10));
+        let frame = publish(&mut samples);
+        let read = snapshot.read(Channel::Four, &frame, &channel, at(16));
+        assert!(read.state.a);
+    }
+}
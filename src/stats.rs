@@ -0,0 +1,127 @@
+use crate::debug::M64Message;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Measurements of the synchronous reads of the adapter. How old the input is when the game reads
+/// it is measured by [`ChannelLatency`](crate::latency::ChannelLatency).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PollStats {
+    /// How many times the adapter has been read by `GetKeys` in synchronous mode.
+    pub sync_polls: u64,
+    /// How many of the synchronous reads failed, so that the cached state was used instead.
+    pub sync_fallbacks: u64,
+    /// The total time `GetKeys` has waited for synchronous reads.
+    pub total_sync_poll_time: Duration,
+}
+
+impl PollStats {
+    pub const fn new() -> Self {
+        Self {
+            sync_polls: 0,
+            sync_fallbacks: 0,
+            total_sync_poll_time: Duration::ZERO,
+        }
+    }
+
+    pub fn record_sync_poll(&mut self, time: Duration, ok: bool) {
+        self.sync_polls += 1;
+        self.total_sync_poll_time += time;
+        if !ok {
+            self.sync_fallbacks += 1;
+        }
+    }
+
+    pub fn mean_sync_poll_time(&self) -> Duration {
+        self.total_sync_poll_time / self.sync_polls.max(1) as u32
+    }
+
+    pub fn report(&self) {
+        if self.sync_polls == 0 {
+            return;
+        }
+
+        debug_print!(
+            M64Message::Status,
+            "Synchronous reads took {:.2} ms on average, and {} of {} fell back to the cached state",
+            self.mean_sync_poll_time().as_secs_f64() * 1000.0,
+            self.sync_fallbacks,
+            self.sync_polls
+        );
+    }
+}
+
+/// The counters of [`PollStats`], kept in atomics so that `GetKeys` records its reads without
+/// waiting for someone reading the stats.
+#[derive(Debug)]
+pub struct AtomicPollStats {
+    sync_polls: AtomicU64,
+    sync_fallbacks: AtomicU64,
+    total_sync_poll_nanos: AtomicU64,
+}
+
+impl AtomicPollStats {
+    pub const fn new() -> Self {
+        Self {
+            sync_polls: AtomicU64::new(0),
+            sync_fallbacks: AtomicU64::new(0),
+            total_sync_poll_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_sync_poll(&self, time: Duration, ok: bool) {
+        let nanos = time.as_nanos().min(u64::MAX as u128) as u64;
+        self.total_sync_poll_nanos
+            .fetch_add(nanos, Ordering::Relaxed);
+        if !ok {
+            self.sync_fallbacks.fetch_add(1, Ordering::Relaxed);
+        }
+        self.sync_polls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn load(&self) -> PollStats {
+        PollStats {
+            sync_polls: self.sync_polls.load(Ordering::Relaxed),
+            sync_fallbacks: self.sync_fallbacks.load(Ordering::Relaxed),
+            total_sync_poll_time: Duration::from_nanos(
+                self.total_sync_poll_nanos.load(Ordering::Relaxed),
+            ),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.sync_polls.store(0, Ordering::Relaxed);
+        self.sync_fallbacks.store(0, Ordering::Relaxed);
+        self.total_sync_poll_nanos.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for AtomicPollStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_stats() {
+        let mut stats = PollStats::new();
+        stats.record_sync_poll(Duration::from_millis(2), true);
+        stats.record_sync_poll(Duration::from_millis(4), false);
+
+        assert_eq!(Duration::from_millis(3), stats.mean_sync_poll_time());
+        assert_eq!(1, stats.sync_fallbacks);
+
+        let atomic = AtomicPollStats::new();
+        atomic.record_sync_poll(Duration::from_millis(2), true);
+        atomic.record_sync_poll(Duration::from_millis(4), false);
+        assert_eq!(stats, atomic.load());
+
+        atomic.reset();
+        assert_eq!(PollStats::new(), atomic.load());
+    }
+}
@@ -0,0 +1,574 @@
+use crate::{
+    adapter::ControllerState,
+    config::{FixedPosition, N64Button, SnapZone},
+};
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_2, FRAC_PI_4, PI};
+
+/// Maximum deflection of the N64 stick, as produced by `ControllerState::stick_with_deadzone` at
+/// full sensitivity.
+pub const N64_STICK_MAX: f32 = 80.0;
+
+/// Measured positions of the eight notches of a GC stick's gate, relative to the centre of the stick.
+///
+/// The notches start at the right notch and go counter-clockwise.
+pub type Notches = [[i8; 2]; 8];
+
+/// The octagonal gate of an N64 controller, given by how far the stick reaches on the cardinals and
+/// on each axis of the diagonals.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Octagon {
+    pub cardinal: f32,
+    pub diagonal: f32,
+}
+
+impl Octagon {
+    /// A gate with every notch at the same distance from the centre, i.e. no gate emulation.
+    pub const CIRCLE: Octagon = Octagon {
+        cardinal: N64_STICK_MAX,
+        diagonal: N64_STICK_MAX * FRAC_1_SQRT_2,
+    };
+
+    /// The position of the given notch, counting counter-clockwise from the right notch.
+    pub fn notch(&self, index: usize) -> (f32, f32) {
+        let (c, d) = (self.cardinal, self.diagonal);
+        match index % 8 {
+            0 => (c, 0.0),
+            1 => (d, d),
+            2 => (0.0, c),
+            3 => (-d, d),
+            4 => (-c, 0.0),
+            5 => (-d, -d),
+            6 => (0.0, -c),
+            _ => (d, -d),
+        }
+    }
+
+    /// Distance from the centre to the edge of the gate in the direction of `angle` (in radians).
+    pub fn radius_at(&self, angle: f32) -> f32 {
+        // The octagon is symmetric around every multiple of 45 degrees, so fold the angle into the
+        // first octant, which is the edge between (cardinal, 0) and (diagonal, diagonal)
+        let angle = angle.rem_euclid(FRAC_PI_2);
+        let angle = if angle > FRAC_PI_4 {
+            FRAC_PI_2 - angle
+        } else {
+            angle
+        };
+
+        let (c, d) = (self.cardinal, self.diagonal);
+
+        // Intersect the ray with the line through the two corners of the edge
+        c * d / (d * angle.cos() + (c - d) * angle.sin())
+    }
+
+    /// Map a stick position in N64 units onto the gate.
+    ///
+    /// A deflection of `N64_STICK_MAX` in any direction reaches the edge of the gate, and the
+    /// stick can never be pushed beyond it.
+    pub fn apply(&self, x: i8, y: i8) -> (i8, i8) {
+        if x == 0 && y == 0 {
+            return (0, 0);
+        }
+
+        let (x, y) = (x as f32, y as f32);
+        let angle = y.atan2(x);
+        let magnitude = (x.hypot(y) / N64_STICK_MAX).min(1.0);
+        let radius = magnitude * self.radius_at(angle);
+
+        (
+            (radius * angle.cos()).round() as i8,
+            (radius * angle.sin()).round() as i8,
+        )
+    }
+}
+
+/// Snap the angle of the stick to the angle of a zone when the stick is within that zone, keeping
+/// the magnitude. The closest zone is used if zones overlap.
+pub fn snap(x: i8, y: i8, zones: &[SnapZone]) -> (i8, i8) {
+    if x == 0 && y == 0 {
+        return (x, y);
+    }
+
+    let (fx, fy) = (x as f32, y as f32);
+    let angle = fy.atan2(fx).to_degrees();
+    let distance = |zone: &SnapZone| ((angle - zone.angle + 180.0).rem_euclid(360.0) - 180.0).abs();
+
+    let Some(zone) = zones
+        .iter()
+        .filter(|z| distance(z) <= z.width / 2.0)
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+    else {
+        return (x, y);
+    };
+
+    let magnitude = fx.hypot(fy);
+    let angle = zone.angle.to_radians();
+    (
+        (magnitude * angle.cos()).round() as i8,
+        (magnitude * angle.sin()).round() as i8,
+    )
+}
+
+/// Get the position of the first fixed position binding that is held, given the state and the
+/// current position of the control stick.
+pub fn fixed_position(
+    state: &ControllerState,
+    x: i8,
+    y: i8,
+    bindings: &[FixedPosition],
+) -> Option<(i8, i8)> {
+    let angle = (x != 0 || y != 0).then(|| (y as f32).atan2(x as f32).to_degrees());
+
+    let binding = bindings.iter().find(|b| {
+        b.button.is_pressed(state)
+            && b.direction.is_none_or(|d| {
+                angle.is_some_and(|a| {
+                    Directions::at_angle(a, 45.0) == Directions::at_angle(d.angle(), 45.0)
+                })
+            })
+    })?;
+
+    match angle {
+        Some(angle) if binding.rotate => {
+            let (sin, cos) = (angle - 90.0).to_radians().sin_cos();
+            let (bx, by) = (binding.x as f32, binding.y as f32);
+            Some((
+                (bx * cos - by * sin).round() as i8,
+                (bx * sin + by * cos).round() as i8,
+            ))
+        }
+        _ => Some((binding.x, binding.y)),
+    }
+}
+
+/// Get the position of the control stick when it is moved by the D-pad, or `None` if the D-pad is
+/// not pressed. Diagonals are scaled so that they are just as far out as the cardinals.
+pub fn d_pad_position(state: &ControllerState, magnitude: u8) -> Option<(i8, i8)> {
+    let x = state.right as i8 - state.left as i8;
+    let y = state.up as i8 - state.down as i8;
+    if x == 0 && y == 0 {
+        return None;
+    }
+
+    let magnitude = magnitude.min(i8::MAX as u8) as f32;
+    let magnitude = if x != 0 && y != 0 {
+        magnitude * FRAC_1_SQRT_2
+    } else {
+        magnitude
+    };
+
+    Some((
+        (x as f32 * magnitude).round() as i8,
+        (y as f32 * magnitude).round() as i8,
+    ))
+}
+
+/// Directions of a stick that is converted to buttons.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Directions {
+    pub right: bool,
+    pub up: bool,
+    pub left: bool,
+    pub down: bool,
+}
+
+impl Directions {
+    pub const NONE: Directions = Directions {
+        right: false,
+        up: false,
+        left: false,
+        down: false,
+    };
+
+    /// The directions for the given angle (in degrees), where diagonals are pressed when the angle
+    /// is within a sector of `diagonal_width` degrees around a diagonal. With a width of 0, one
+    /// direction is pressed even on an exact diagonal.
+    pub fn at_angle(angle: f32, diagonal_width: f32) -> Self {
+        let angle = angle.rem_euclid(360.0);
+        let diagonal = ((angle - 45.0) / 90.0).round() * 90.0 + 45.0;
+
+        let (a, b) = if diagonal_width > 0.0 && (angle - diagonal).abs() <= diagonal_width / 2.0 {
+            (diagonal - 45.0, diagonal + 45.0)
+        } else {
+            let cardinal = (angle / 90.0).round() * 90.0;
+            (cardinal, cardinal)
+        };
+
+        let mut directions = Directions::NONE;
+        for cardinal in [a, b] {
+            match cardinal.rem_euclid(360.0) as u32 {
+                0 => directions.right = true,
+                90 => directions.up = true,
+                180 => directions.left = true,
+                _ => directions.down = true,
+            }
+        }
+
+        directions
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Directions::NONE
+    }
+
+    /// Get the bit pattern for the N64 buttons of the pressed directions.
+    pub fn bit_pattern(
+        &self,
+        right: N64Button,
+        up: N64Button,
+        left: N64Button,
+        down: N64Button,
+    ) -> u32 {
+        let mut buttons = 0;
+        if self.right {
+            buttons |= right.bit_pattern();
+        }
+        if self.up {
+            buttons |= up.bit_pattern();
+        }
+        if self.left {
+            buttons |= left.bit_pattern();
+        }
+        if self.down {
+            buttons |= down.bit_pattern();
+        }
+        buttons
+    }
+}
+
+/// How far (in degrees) the angle of a stick has to move into a new sector before the pressed
+/// directions change.
+const SECTOR_HYSTERESIS: f32 = 5.0;
+
+/// Converts a stick to directional buttons, with separate thresholds for pressing and releasing
+/// the buttons so that they do not chatter.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StickButtons {
+    pressed: Directions,
+}
+
+impl StickButtons {
+    pub const fn new() -> Self {
+        Self {
+            pressed: Directions::NONE,
+        }
+    }
+
+    /// Convert each axis on its own, so that a direction is pressed whenever the stick is far
+    /// enough out on that axis.
+    pub fn update_axes(&mut self, x: i8, y: i8, press: u8, release: u8) -> Directions {
+        let axis = |value: i8, pressed: bool| {
+            let threshold = if pressed { release } else { press };
+            value.unsigned_abs() >= threshold.max(1)
+        };
+
+        self.pressed = Directions {
+            right: x > 0 && axis(x, self.pressed.right),
+            up: y > 0 && axis(y, self.pressed.up),
+            left: x < 0 && axis(x, self.pressed.left),
+            down: y < 0 && axis(y, self.pressed.down),
+        };
+
+        self.pressed
+    }
+
+    /// Convert the stick by the angle it is pointing at, with diagonals being pressed in sectors
+    /// of `diagonal_width` degrees around each diagonal.
+    pub fn update_sectors(
+        &mut self,
+        x: i8,
+        y: i8,
+        press: u8,
+        release: u8,
+        diagonal_width: f32,
+    ) -> Directions {
+        let radius = (x as f32).hypot(y as f32);
+        let threshold = if self.pressed.is_none() {
+            press
+        } else {
+            release
+        };
+        if radius < threshold.max(1) as f32 {
+            self.pressed = Directions::NONE;
+            return self.pressed;
+        }
+
+        let angle = (y as f32).atan2(x as f32).to_degrees();
+        let directions = Directions::at_angle(angle, diagonal_width);
+
+        // Keep the previous directions while close to the edge of their sector
+        let near_previous = [angle - SECTOR_HYSTERESIS, angle + SECTOR_HYSTERESIS]
+            .iter()
+            .any(|&a| Directions::at_angle(a, diagonal_width) == self.pressed);
+        if !near_previous {
+            self.pressed = directions;
+        }
+
+        self.pressed
+    }
+}
+
+fn cross((ax, ay): (f32, f32), (bx, by): (f32, f32)) -> f32 {
+    ax * by - ay * bx
+}
+
+fn notch_f32(notches: &Notches, index: usize) -> (f32, f32) {
+    let [x, y] = notches[index % 8];
+    (x as f32, y as f32)
+}
+
+/// Check that the notches go counter-clockwise around the centre exactly once, which is needed for
+/// `remap_notches` to give a sensible result.
+pub fn notches_valid(notches: &Notches) -> bool {
+    let mut total_angle = 0.0;
+    for i in 0..8 {
+        let (a, b) = (notch_f32(notches, i), notch_f32(notches, i + 1));
+        let turn = cross(a, b);
+        if turn <= 0.0 {
+            return false;
+        }
+
+        total_angle += turn.atan2(a.0 * b.0 + a.1 * b.1);
+    }
+
+    (total_angle - 2.0 * PI).abs() < 0.01
+}
+
+/// Remap a stick position (relative to the centre of the stick) using the measured notches of the
+/// stick, so that each notch lands exactly on the corresponding notch of the gate.
+///
+/// Positions between two notches are interpolated linearly, and the stick can never be pushed
+/// beyond the line between two notches. The deadzone is applied before remapping, and is scaled so
+/// that the notches are still reached.
+pub fn remap_notches(x: i8, y: i8, deadzone: u8, notches: &Notches, gate: &Octagon) -> (i8, i8) {
+    let v = (x as f32, y as f32);
+    let radius = v.0.hypot(v.1);
+    let deadzone = deadzone as f32;
+    if radius <= deadzone {
+        return (0, 0);
+    }
+
+    // Find the two notches the position lies between
+    let Some(i) = (0..8).find(|&i| {
+        cross(notch_f32(notches, i), v) >= 0.0 && cross(v, notch_f32(notches, i + 1)) >= 0.0
+    }) else {
+        return (0, 0);
+    };
+
+    // Express the position as a combination of the two notches
+    let (n0, n1) = (notch_f32(notches, i), notch_f32(notches, i + 1));
+    let det = cross(n0, n1);
+    let mut a = cross(v, n1) / det;
+    let mut b = cross(n0, v) / det;
+
+    let sum = a + b;
+    let rim = radius / sum;
+    if sum > 1.0 {
+        a /= sum;
+        b /= sum;
+    }
+
+    let scale = if rim > deadzone && radius < rim {
+        (radius - deadzone) * rim / ((rim - deadzone) * radius)
+    } else {
+        1.0
+    };
+
+    let (g0, g1) = (gate.notch(i), gate.notch(i + 1));
+    let out_x = (a * g0.0 + b * g1.0) * scale;
+    let out_y = (a * g0.1 + b * g1.1) * scale;
+
+    (out_x.round() as i8, out_y.round() as i8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OEM: Octagon = Octagon {
+        cardinal: 80.0,
+        diagonal: 70.0,
+    };
+
+    #[test]
+    fn test_octagon_corners() {
+        assert_eq!((80, 0), OEM.apply(80, 0));
+        assert_eq!((0, -80), OEM.apply(0, -100));
+        assert_eq!((70, 70), OEM.apply(57, 57));
+        assert_eq!((-70, 70), OEM.apply(-127, 127));
+    }
+
+    #[test]
+    fn test_octagon_partial_deflection() {
+        assert_eq!((0, 0), OEM.apply(0, 0));
+        assert_eq!((40, 0), OEM.apply(40, 0));
+        assert_eq!((-35, -35), OEM.apply(-28, -28));
+    }
+
+    #[test]
+    fn test_snap() {
+        let zones = [
+            SnapZone {
+                angle: 0.0,
+                width: 10.0,
+            },
+            SnapZone {
+                angle: 45.0,
+                width: 20.0,
+            },
+            SnapZone {
+                angle: -90.0,
+                width: 10.0,
+            },
+        ];
+
+        assert_eq!((60, 0), snap(60, 3, &zones));
+        assert_eq!((57, 57), snap(60, 54, &zones));
+        assert_eq!((0, -80), snap(-5, -80, &zones));
+        assert_eq!((60, 10), snap(60, 10, &zones));
+        assert_eq!((0, 0), snap(0, 0, &zones));
+    }
+
+    #[test]
+    fn test_fixed_position() {
+        use crate::config::{GcButton, StickDirection};
+
+        let bindings = [
+            FixedPosition {
+                button: GcButton::X,
+                direction: Some(StickDirection::UpLeft),
+                x: -27,
+                y: 27,
+                rotate: false,
+            },
+            FixedPosition {
+                button: GcButton::X,
+                direction: None,
+                x: 0,
+                y: 8,
+                rotate: true,
+            },
+        ];
+        let mut state = ControllerState::new();
+
+        assert_eq!(None, fixed_position(&state, 0, 0, &bindings));
+
+        state.x = true;
+        assert_eq!(Some((-27, 27)), fixed_position(&state, -50, 45, &bindings));
+        assert_eq!(Some((0, 8)), fixed_position(&state, 0, 0, &bindings));
+        assert_eq!(Some((8, 0)), fixed_position(&state, 60, 0, &bindings));
+        assert_eq!(Some((0, -8)), fixed_position(&state, 2, -70, &bindings));
+    }
+
+    #[test]
+    fn test_d_pad_position() {
+        let mut state = ControllerState::new();
+        assert_eq!(None, d_pad_position(&state, 80));
+
+        state.up = true;
+        assert_eq!(Some((0, 80)), d_pad_position(&state, 80));
+
+        state.left = true;
+        assert_eq!(Some((-57, 57)), d_pad_position(&state, 80));
+
+        state.right = true;
+        assert_eq!(Some((0, 127)), d_pad_position(&state, 200));
+    }
+
+    #[test]
+    fn test_directions_at_angle() {
+        let right = Directions {
+            right: true,
+            ..Directions::NONE
+        };
+        let up_left = Directions {
+            up: true,
+            left: true,
+            ..Directions::NONE
+        };
+
+        assert_eq!(right, Directions::at_angle(30.0, 0.0));
+        assert_eq!(right, Directions::at_angle(-44.0, 0.0));
+        assert_eq!(right, Directions::at_angle(22.0, 45.0));
+        assert_eq!(right, Directions::at_angle(30.0, 20.0));
+        assert_eq!(up_left, Directions::at_angle(120.0, 45.0));
+        assert_eq!(up_left, Directions::at_angle(-215.0, 20.0));
+
+        // Only one direction on an exact diagonal without diagonals
+        let count = |d: Directions| {
+            [d.right, d.up, d.left, d.down]
+                .iter()
+                .filter(|&&p| p)
+                .count()
+        };
+        assert_eq!(1, count(Directions::at_angle(45.0, 0.0)));
+        assert_eq!(1, count(Directions::at_angle(135.0, 0.0)));
+        assert_eq!(
+            1,
+            count(StickButtons::new().update_sectors(50, 50, 40, 30, 0.0))
+        );
+        assert_eq!(
+            1,
+            count(StickButtons::new().update_sectors(-60, 60, 40, 30, 0.0))
+        );
+    }
+
+    #[test]
+    fn test_stick_buttons_hysteresis() {
+        let mut buttons = StickButtons::new();
+
+        assert!(buttons.update_sectors(30, 2, 40, 25, 45.0).is_none());
+        assert!(buttons.update_sectors(45, 2, 40, 25, 45.0).right);
+        assert!(buttons.update_sectors(30, 2, 40, 25, 45.0).right);
+        assert!(buttons.update_sectors(20, 2, 40, 25, 45.0).is_none());
+
+        // Slightly past the edge between right and up-right
+        buttons.update_sectors(60, 0, 40, 25, 45.0);
+        assert!(!buttons.update_sectors(60, 27, 40, 25, 45.0).up);
+        assert!(buttons.update_sectors(60, 40, 40, 25, 45.0).up);
+    }
+
+    #[test]
+    fn test_remap_notches() {
+        // A slightly rotated and uneven gate
+        let notches = [
+            [74, 6],
+            [50, 55],
+            [-4, 76],
+            [-55, 49],
+            [-75, -5],
+            [-50, -53],
+            [5, -73],
+            [54, -48],
+        ];
+        assert!(notches_valid(&notches));
+
+        for (i, &[x, y]) in notches.iter().enumerate() {
+            let (gx, gy) = OEM.notch(i);
+            assert_eq!(
+                (gx as i8, gy as i8),
+                remap_notches(x, y, 10, &notches, &OEM)
+            );
+        }
+
+        assert_eq!((0, 0), remap_notches(5, -5, 10, &notches, &OEM));
+        assert_eq!((80, 0), remap_notches(100, 8, 10, &notches, &OEM));
+    }
+
+    #[test]
+    fn test_invalid_notches() {
+        let mut notches = [
+            [80, 0],
+            [57, 57],
+            [0, 80],
+            [-57, 57],
+            [-80, 0],
+            [-57, -57],
+            [0, -80],
+            [57, -57],
+        ];
+        assert!(notches_valid(&notches));
+
+        notches.swap(2, 3);
+        assert!(!notches_valid(&notches));
+    }
+}
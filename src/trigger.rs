@@ -0,0 +1,76 @@
+use crate::config::{N64Button, TriggerConfig};
+
+/// Converts an analog trigger to N64 buttons, with a light press past the threshold and a full
+/// press at the digital click.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TriggerButtons {
+    light_pressed: bool,
+}
+
+impl TriggerButtons {
+    pub const fn new() -> Self {
+        Self {
+            light_pressed: false,
+        }
+    }
+
+    /// Get the bit pattern of the N64 buttons pressed by the trigger, given its digital click and
+    /// analog value. `full_press` is the button from the controller mapping.
+    pub fn update(
+        &mut self,
+        click: bool,
+        value: u8,
+        cfg: &TriggerConfig,
+        default_threshold: u8,
+        full_press: N64Button,
+    ) -> u32 {
+        let depth = cfg.depth(value);
+        let threshold = cfg.threshold.unwrap_or(default_threshold);
+        self.light_pressed = if self.light_pressed {
+            depth > threshold.saturating_sub(cfg.hysteresis)
+        } else {
+            depth > threshold
+        };
+
+        match cfg.light_press {
+            Some(light_press) => {
+                let mut buttons = 0;
+                if self.light_pressed {
+                    buttons |= light_press.bit_pattern();
+                }
+                if click {
+                    buttons |= full_press.bit_pattern();
+                }
+                buttons
+            }
+            None if click || self.light_pressed => full_press.bit_pattern(),
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_two_stage_trigger() {
+        let cfg = TriggerConfig {
+            light_press: Some(N64Button::Z),
+            threshold: Some(100),
+            hysteresis: 20,
+            rest: 30,
+            max: 230,
+        };
+        let mut trigger = TriggerButtons::new();
+        let z = N64Button::Z.bit_pattern();
+        let r = N64Button::R.bit_pattern();
+
+        assert_eq!(0, trigger.update(false, 30, &cfg, 168, N64Button::R));
+        assert_eq!(0, trigger.update(false, 100, &cfg, 168, N64Button::R));
+        assert_eq!(z, trigger.update(false, 120, &cfg, 168, N64Button::R));
+        assert_eq!(z, trigger.update(false, 100, &cfg, 168, N64Button::R));
+        assert_eq!(z | r, trigger.update(true, 230, &cfg, 168, N64Button::R));
+        assert_eq!(0, trigger.update(false, 80, &cfg, 168, N64Button::R));
+    }
+}
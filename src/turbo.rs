@@ -0,0 +1,197 @@
+use crate::{
+    adapter::{AdapterState, Channel, ControllerState},
+    config::{Chord, Config, ControllerMapping, GcButton, TurboConfig},
+    debug::M64Message,
+};
+use std::time::{Duration, Instant};
+
+/// Check if a turbo button that has been held for `elapsed` is pressed at the moment.
+fn is_pressed(elapsed: Duration, cfg: &TurboConfig) -> bool {
+    if cfg.frequency <= 0.0 {
+        return true;
+    }
+
+    let period = 1.0 / cfg.frequency;
+    let t = elapsed.as_secs_f32() % period;
+    t < cfg.duty_cycle.clamp(0.0, 1.0) * period
+}
+
+#[derive(Debug, Copy, Clone)]
+struct TurboChannel {
+    /// N64 buttons where turbo has been turned on or off with the hotkey, relative to the config.
+    toggled: u32,
+    /// When each of the GC buttons was pressed.
+    pressed_at: [Option<Instant>; 12],
+}
+
+impl TurboChannel {
+    const fn new() -> Self {
+        Self {
+            toggled: 0,
+            pressed_at: [None; 12],
+        }
+    }
+
+    fn update(
+        &mut self,
+        i: usize,
+        s: &mut ControllerState,
+        cfg: &TurboConfig,
+        mapping: &ControllerMapping,
+        hotkey: &Chord,
+        now: Instant,
+    ) {
+        let hotkey_held = hotkey.is_held(s);
+        let configured = cfg.buttons.iter().fold(0, |bits, b| bits | b.bit_pattern());
+
+        for (index, (button, pressed_at)) in
+            GcButton::ALL.iter().zip(&mut self.pressed_at).enumerate()
+        {
+            if !button.is_pressed(s) {
+                *pressed_at = None;
+                continue;
+            }
+
+            let n64_button = mapping.get(*button);
+            let bit = n64_button.bit_pattern();
+            let since = match pressed_at {
+                Some(since) => *since,
+                None => {
+                    if hotkey_held && !hotkey.0.contains(button) && bit != 0 {
+                        self.toggled ^= bit;
+                        debug_print!(
+                            M64Message::Status,
+                            "Turbo {} for {:?} on controller {}",
+                            if (configured ^ self.toggled) & bit != 0 {
+                                "on"
+                            } else {
+                                "off"
+                            },
+                            n64_button,
+                            i + 1
+                        );
+                    }
+                    *pressed_at.insert(now)
+                }
+            };
+
+            if (configured ^ self.toggled) & bit != 0 {
+                s.turbo_buttons |= 1 << index;
+                if !is_pressed(now - since, cfg) {
+                    button.set_pressed(s, false);
+                }
+            }
+        }
+    }
+}
+
+/// Turbo for the buttons of each controller.
+///
+/// Buttons are released and pressed again on the timeline of the adapter thread, starting when the
+/// button is pressed, so that the rate does not depend on how often `GetKeys` is called. This runs
+/// last on every state read from the adapter, so that hotkeys see the buttons as they are held.
+/// The buttons are marked in `turbo_buttons`, so that `GetKeys` sees them as they are when it
+/// reads them, instead of pressed if they were pressed at any time since the last read.
+#[derive(Debug)]
+pub struct Turbo {
+    channels: [TurboChannel; 4],
+}
+
+impl Turbo {
+    pub const fn new() -> Self {
+        Self {
+            channels: [TurboChannel::new(); 4],
+        }
+    }
+
+    pub fn update(&mut self, state: &mut AdapterState, cfg: &Config, now: Instant) {
+        for (i, turbo) in self.channels.iter_mut().enumerate() {
+            let channel = Channel::try_from(i).unwrap();
+            let s = state.controller_state_mut(channel);
+            if !s.is_connected() {
+                turbo.pressed_at = [None; 12];
+                continue;
+            }
+
+            let (mapping, _) = cfg.mapping(s);
+            turbo.update(
+                i,
+                s,
+                &cfg.channel(channel).turbo,
+                mapping,
+                &cfg.hotkeys.turbo,
+                now,
+            );
+        }
+    }
+}
+
+impl Default for Turbo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::N64Button;
+
+    #[test]
+    fn test_turbo() {
+        let cfg = Config::default();
+        let turbo_cfg = TurboConfig {
+            buttons: vec![N64Button::A],
+            frequency: 10.0,
+            duty_cycle: 0.5,
+        };
+        let mut turbo = TurboChannel::new();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let update = |turbo: &mut TurboChannel, s: &mut ControllerState, ms| {
+            turbo.update(
+                0,
+                s,
+                &turbo_cfg,
+                &cfg.controller_mapping,
+                &cfg.hotkeys.turbo,
+                at(ms),
+            );
+        };
+
+        let mut s;
+        let held = || ControllerState {
+            a: true,
+            b: true,
+            ..ControllerState::new()
+        };
+
+        // A repeats from when it was pressed, B is held
+        for (ms, pressed) in [
+            (10, true),
+            (40, true),
+            (70, false),
+            (110, true),
+            (160, false),
+        ] {
+            s = held();
+            update(&mut turbo, &mut s, ms);
+            assert_eq!(pressed, s.a, "at {ms} ms");
+            assert!(s.b);
+            assert_eq!(1, s.turbo_buttons);
+        }
+
+        // Turn turbo off for A with the hotkey
+        s = ControllerState {
+            z: true,
+            down: true,
+            ..ControllerState::new()
+        };
+        update(&mut turbo, &mut s, 200);
+        s.a = true;
+        update(&mut turbo, &mut s, 210);
+        s = held();
+        update(&mut turbo, &mut s, 270);
+        assert!(s.a);
+    }
+}
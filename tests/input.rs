@@ -47,7 +47,11 @@ fn receives_input() {
     let adapter = GcAdapter::new().expect(ERR);
     let started = Instant::now();
 
-    let state = AdapterState::from(adapter.read().unwrap());
+    let state = adapter
+        .read()
+        .unwrap()
+        .map(AdapterState::from)
+        .unwrap_or_default();
 
     if !state.any_connected() {
         eprintln!("no controllers detected, but might be a false negative");
@@ -59,7 +63,9 @@ fn receives_input() {
             break;
         }
 
-        let buf = adapter.read().unwrap();
+        let Some(buf) = adapter.read().unwrap() else {
+            continue;
+        };
         let state = AdapterState::from(buf);
         for (i, s) in (0..4)
             .zip(all_controller_states(&state))